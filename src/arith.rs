@@ -0,0 +1,449 @@
+use logos::Logos;
+use std::fmt;
+
+use crate::exec::ExecContext;
+
+#[derive(Logos, Debug, Clone, Copy, PartialEq, Eq)]
+#[logos(skip r"[ \t\n]+")]
+enum ArithToken {
+    #[regex(r"[0-9]+")]
+    Number,
+    #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*")]
+    Name,
+    #[token("(")]
+    OpenParen,
+    #[token(")")]
+    CloseParen,
+    #[token("+")]
+    Plus,
+    #[token("-")]
+    Minus,
+    #[token("*")]
+    Times,
+    #[token("/")]
+    Divide,
+    #[token("%")]
+    Remainder,
+    #[token("**")]
+    Power,
+    #[token("<<")]
+    ShiftLeft,
+    #[token(">>")]
+    ShiftRight,
+    #[token("<")]
+    Less,
+    #[token("<=")]
+    LessEqual,
+    #[token(">")]
+    Greater,
+    #[token(">=")]
+    GreaterEqual,
+    #[token("==")]
+    Equal,
+    #[token("!=")]
+    NotEqual,
+    #[token("&")]
+    BitAnd,
+    #[token("^")]
+    BitXor,
+    #[token("|")]
+    BitOr,
+    #[token("~")]
+    BitNot,
+    #[token("!")]
+    Not,
+    #[token("&&")]
+    And,
+    #[token("||")]
+    Or,
+    #[token("?")]
+    Question,
+    #[token(":")]
+    Colon,
+    #[token("=")]
+    Assign,
+    /// Any of `+=`, `-=`, `*=`, `/=`, `%=`, `<<=`, `>>=`, `&=`, `^=` and `|=`
+    #[regex(r"(\+|-|\*|/|%|<<|>>|&|\^|\|)=")]
+    AssignOperator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Power,
+    ShiftLeft,
+    ShiftRight,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    BitAnd,
+    BitXor,
+    BitOr,
+}
+
+impl BinaryOp {
+    /// The operator a token stands for, along with how tightly it binds.
+    fn from_token(token: ArithToken) -> Option<(BinaryOp, u8)> {
+        Some(match token {
+            ArithToken::Power => (BinaryOp::Power, 9),
+            ArithToken::Times => (BinaryOp::Multiply, 8),
+            ArithToken::Divide => (BinaryOp::Divide, 8),
+            ArithToken::Remainder => (BinaryOp::Remainder, 8),
+            ArithToken::Plus => (BinaryOp::Add, 7),
+            ArithToken::Minus => (BinaryOp::Subtract, 7),
+            ArithToken::ShiftLeft => (BinaryOp::ShiftLeft, 6),
+            ArithToken::ShiftRight => (BinaryOp::ShiftRight, 6),
+            ArithToken::Less => (BinaryOp::Less, 5),
+            ArithToken::LessEqual => (BinaryOp::LessEqual, 5),
+            ArithToken::Greater => (BinaryOp::Greater, 5),
+            ArithToken::GreaterEqual => (BinaryOp::GreaterEqual, 5),
+            ArithToken::Equal => (BinaryOp::Equal, 4),
+            ArithToken::NotEqual => (BinaryOp::NotEqual, 4),
+            ArithToken::BitAnd => (BinaryOp::BitAnd, 3),
+            ArithToken::BitXor => (BinaryOp::BitXor, 2),
+            ArithToken::BitOr => (BinaryOp::BitOr, 1),
+            _ => return None,
+        })
+    }
+
+    /// The operator in a compound assignment like `+=`.
+    fn from_assignment(operator: &str) -> Option<BinaryOp> {
+        let operator = operator.strip_suffix('=')?;
+        let token = ArithToken::lexer(operator).next()?.ok()?;
+        BinaryOp::from_token(token).map(|(op, _)| op)
+    }
+
+    fn apply(self, left: i64, right: i64) -> Result<i64, ArithError> {
+        let truth = |condition: bool| condition as i64;
+        Ok(match self {
+            BinaryOp::Add => left.wrapping_add(right),
+            BinaryOp::Subtract => left.wrapping_sub(right),
+            BinaryOp::Multiply => left.wrapping_mul(right),
+            BinaryOp::Divide | BinaryOp::Remainder if right == 0 => {
+                return Err(ArithError::DivisionByZero)
+            }
+            BinaryOp::Divide => left.wrapping_div(right),
+            BinaryOp::Remainder => left.wrapping_rem(right),
+            BinaryOp::Power if right < 0 => return Err(ArithError::NegativeExponent),
+            BinaryOp::Power => wrapping_pow(left, right as u64),
+            BinaryOp::ShiftLeft => left.wrapping_shl(right as u32),
+            BinaryOp::ShiftRight => left.wrapping_shr(right as u32),
+            BinaryOp::Less => truth(left < right),
+            BinaryOp::LessEqual => truth(left <= right),
+            BinaryOp::Greater => truth(left > right),
+            BinaryOp::GreaterEqual => truth(left >= right),
+            BinaryOp::Equal => truth(left == right),
+            BinaryOp::NotEqual => truth(left != right),
+            BinaryOp::BitAnd => left & right,
+            BinaryOp::BitXor => left ^ right,
+            BinaryOp::BitOr => left | right,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnaryOp {
+    Negate,
+    Plus,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Variable(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `name = value`, or a compound assignment like `name += value`
+    Assign(String, Option<BinaryOp>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArithError {
+    InvalidSyntax,
+    DivisionByZero,
+    NegativeExponent,
+    /// A variable used in an expression holds something other than an integer.
+    NotANumber(String),
+}
+
+impl fmt::Display for ArithError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArithError::InvalidSyntax => write!(f, "invalid arithmetic syntax"),
+            ArithError::DivisionByZero => write!(f, "division by 0"),
+            ArithError::NegativeExponent => write!(f, "exponent less than 0"),
+            ArithError::NotANumber(name) => write!(f, "{}: not a number", name),
+        }
+    }
+}
+
+/// Parses the tokens of an arithmetic expression, by precedence climbing for binary operators.
+struct Parser<'a> {
+    tokens: Vec<(ArithToken, &'a str)>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Result<Parser<'a>, ArithError> {
+        let mut tokens = vec![];
+        let mut lexer = ArithToken::lexer(source);
+        while let Some(token) = lexer.next() {
+            let token = token.map_err(|_| ArithError::InvalidSyntax)?;
+            tokens.push((token, lexer.slice()));
+        }
+        Ok(Parser {
+            tokens,
+            position: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<ArithToken> {
+        self.tokens.get(self.position).map(|(token, _)| *token)
+    }
+
+    fn next(&mut self) -> Option<(ArithToken, &'a str)> {
+        let token = self.tokens.get(self.position).copied();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: ArithToken) -> Result<(), ArithError> {
+        match self.next() {
+            Some((token, _)) if token == expected => Ok(()),
+            _ => Err(ArithError::InvalidSyntax),
+        }
+    }
+
+    /// Parses a whole expression, which must use up every token.
+    fn parse(mut self) -> Result<Expr, ArithError> {
+        let expr = self.parse_assignment()?;
+        match self.peek() {
+            Some(_) => Err(ArithError::InvalidSyntax),
+            None => Ok(expr),
+        }
+    }
+
+    /// Parses an assignment, or the conditional expression that makes up everything else.
+    fn parse_assignment(&mut self) -> Result<Expr, ArithError> {
+        if let [(ArithToken::Name, name), (operator, text), ..] = self.tokens[self.position..] {
+            let operator = match operator {
+                ArithToken::Assign => Some(None),
+                ArithToken::AssignOperator => Some(BinaryOp::from_assignment(text)),
+                _ => None,
+            };
+            if let Some(operator) = operator {
+                self.position += 2;
+                let value = self.parse_assignment()?;
+                return Ok(Expr::Assign(name.to_string(), operator, Box::new(value)));
+            }
+        }
+        self.parse_conditional()
+    }
+
+    fn parse_conditional(&mut self) -> Result<Expr, ArithError> {
+        let condition = self.parse_or()?;
+        if self.peek() != Some(ArithToken::Question) {
+            return Ok(condition);
+        }
+        self.next();
+        let then = self.parse_assignment()?;
+        self.expect(ArithToken::Colon)?;
+        let otherwise = self.parse_assignment()?;
+        Ok(Expr::Conditional(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ArithError> {
+        let mut left = self.parse_and()?;
+        while self.peek() == Some(ArithToken::Or) {
+            self.next();
+            left = Expr::Or(Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ArithError> {
+        let mut left = self.parse_binary(1)?;
+        while self.peek() == Some(ArithToken::And) {
+            self.next();
+            left = Expr::And(Box::new(left), Box::new(self.parse_binary(1)?));
+        }
+        Ok(left)
+    }
+
+    /// Parses a chain of binary operators that bind at least as tightly as `min_precedence`.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, ArithError> {
+        let mut left = self.parse_unary()?;
+        while let Some((op, precedence)) = self.peek().and_then(BinaryOp::from_token) {
+            if precedence < min_precedence {
+                break;
+            }
+            self.next();
+            // `**` is right associative, everything else is left associative
+            let next_precedence = if op == BinaryOp::Power {
+                precedence
+            } else {
+                precedence + 1
+            };
+            let right = self.parse_binary(next_precedence)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ArithError> {
+        let op = match self.peek() {
+            Some(ArithToken::Minus) => UnaryOp::Negate,
+            Some(ArithToken::Plus) => UnaryOp::Plus,
+            Some(ArithToken::Not) => UnaryOp::Not,
+            Some(ArithToken::BitNot) => UnaryOp::BitNot,
+            _ => return self.parse_primary(),
+        };
+        self.next();
+        Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ArithError> {
+        match self.next() {
+            Some((ArithToken::Number, text)) => text
+                .parse()
+                .map(Expr::Number)
+                .map_err(|_| ArithError::InvalidSyntax),
+            Some((ArithToken::Name, name)) => Ok(Expr::Variable(name.to_string())),
+            Some((ArithToken::OpenParen, _)) => {
+                let expr = self.parse_assignment()?;
+                self.expect(ArithToken::CloseParen)?;
+                Ok(expr)
+            }
+            _ => Err(ArithError::InvalidSyntax),
+        }
+    }
+}
+
+impl ExecContext {
+    /// Evaluates an arithmetic expression whose parameters and commands have already been
+    /// substituted. Variables are stored as strings, so they are read as integers when named in
+    /// an expression, with unset or empty variables counting as 0.
+    pub fn evaluate_arithmetic(&mut self, expression: &str) -> Result<i64, ArithError> {
+        let expr = Parser::new(expression)?.parse()?;
+        self.evaluate(&expr)
+    }
+
+    fn evaluate(&mut self, expr: &Expr) -> Result<i64, ArithError> {
+        Ok(match expr {
+            Expr::Number(value) => *value,
+            Expr::Variable(name) => self.integer_variable(name)?,
+            Expr::Unary(op, operand) => {
+                let value = self.evaluate(operand)?;
+                match op {
+                    UnaryOp::Negate => value.wrapping_neg(),
+                    UnaryOp::Plus => value,
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::BitNot => !value,
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.evaluate(left)?;
+                op.apply(left, self.evaluate(right)?)?
+            }
+            Expr::And(left, right) => {
+                (self.evaluate(left)? != 0 && self.evaluate(right)? != 0) as i64
+            }
+            Expr::Or(left, right) => {
+                (self.evaluate(left)? != 0 || self.evaluate(right)? != 0) as i64
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.evaluate(condition)? != 0 {
+                    self.evaluate(then)?
+                } else {
+                    self.evaluate(otherwise)?
+                }
+            }
+            Expr::Assign(name, op, value) => {
+                let mut value = self.evaluate(value)?;
+                if let Some(op) = op {
+                    value = op.apply(self.integer_variable(name)?, value)?;
+                }
+                self.set_variable(name, &value.to_string());
+                value
+            }
+        })
+    }
+
+    fn integer_variable(&self, name: &str) -> Result<i64, ArithError> {
+        let value = self.lookup_variable(name).unwrap_or_default();
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        value
+            .parse()
+            .map_err(|_| ArithError::NotANumber(name.to_string()))
+    }
+}
+
+/// Raises `base` to any power, wrapping around on overflow like the other operators. Squaring
+/// repeatedly keeps this quick even when the exponent is too large for `i64::wrapping_pow`.
+fn wrapping_pow(mut base: i64, mut exponent: u64) -> i64 {
+    let mut result: i64 = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exponent >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_arithmetic() {
+        let mut context = ExecContext::new();
+        let mut evaluate = |expression| context.evaluate_arithmetic(expression);
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9));
+        assert_eq!(evaluate("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(evaluate("-2 ** 2"), Ok(4));
+        assert_eq!(evaluate("2 ** 99999999999"), Ok(0));
+        assert_eq!(evaluate("-1 ** 99999999999"), Ok(-1));
+        assert_eq!(evaluate("3 ** 40"), Ok(3i64.wrapping_pow(40)));
+        assert_eq!(evaluate("2 ** -1"), Err(ArithError::NegativeExponent));
+        assert_eq!(evaluate("7 / 2 + 7 % 2 - 10"), Ok(-6));
+        assert_eq!(evaluate("1 << 4 | 3 & 1 ^ 2"), Ok(19));
+        assert_eq!(evaluate("3 > 2 && 2 >= 3 || !0 == 1"), Ok(1));
+        assert_eq!(evaluate("~0 ? 5 : 6"), Ok(5));
+        assert_eq!(evaluate("i = 2, 3"), Err(ArithError::InvalidSyntax));
+        assert_eq!(evaluate("1 +"), Err(ArithError::InvalidSyntax));
+        assert_eq!(evaluate("1 / 0"), Err(ArithError::DivisionByZero));
+        assert_eq!(evaluate("i += 5"), Ok(5));
+        assert_eq!(evaluate("j = i *= 2"), Ok(10));
+        assert_eq!(evaluate("i + j + unset"), Ok(20));
+        assert_eq!(evaluate("0 && (k = 1)"), Ok(0));
+        assert_eq!(evaluate("k"), Ok(0));
+        context.set_variable("s", "text");
+        assert_eq!(
+            context.evaluate_arithmetic("s + 1"),
+            Err(ArithError::NotANumber("s".to_string()))
+        );
+        assert_eq!(context.get_variable("j"), Some("10"));
+    }
+}
@@ -0,0 +1,261 @@
+use std::env;
+
+use crate::{command::describe_io_error, exec::ExecContext};
+
+/// A command that is executed by the shell itself rather than by spawning a process.
+pub type Builtin = fn(&mut ExecContext, &[String]) -> i32;
+
+pub fn lookup(name: &str) -> Option<Builtin> {
+    match name {
+        "cd" => Some(cd),
+        "pwd" => Some(pwd),
+        "exit" => Some(exit),
+        "export" => Some(export),
+        "set" => Some(set),
+        "shift" => Some(shift),
+        "break" => Some(break_loops),
+        "continue" => Some(continue_loops),
+        "return" => Some(return_from_function),
+        "local" => Some(local),
+        _ => None,
+    }
+}
+
+fn cd(context: &mut ExecContext, args: &[String]) -> i32 {
+    let target = match args.first().map(String::as_str) {
+        None => match context.get_variable("HOME") {
+            Some(home) => home.to_string(),
+            None => {
+                eprintln!("dcsh: cd: HOME not set");
+                return 1;
+            }
+        },
+        Some("-") => match context.get_variable("OLDPWD") {
+            Some(oldpwd) => {
+                println!("{}", oldpwd);
+                oldpwd.to_string()
+            }
+            None => {
+                eprintln!("dcsh: cd: OLDPWD not set");
+                return 1;
+            }
+        },
+        Some(directory) => directory.to_string(),
+    };
+    let previous = env::current_dir();
+    if let Err(err) = env::set_current_dir(&target) {
+        eprintln!("dcsh: cd: {}: {}", target, describe_io_error(&err));
+        return 1;
+    }
+    if let Ok(previous) = previous {
+        context.set_variable("OLDPWD", &previous.to_string_lossy());
+    }
+    if let Ok(current) = env::current_dir() {
+        context.set_variable("PWD", &current.to_string_lossy());
+    }
+    0
+}
+
+fn pwd(_context: &mut ExecContext, _args: &[String]) -> i32 {
+    match env::current_dir() {
+        Ok(directory) => {
+            println!("{}", directory.display());
+            0
+        }
+        Err(err) => {
+            eprintln!("dcsh: pwd: {}", describe_io_error(&err));
+            1
+        }
+    }
+}
+
+fn exit(context: &mut ExecContext, args: &[String]) -> i32 {
+    let status = match args.first() {
+        None => context.status(),
+        Some(code) => match code.parse::<i32>() {
+            Ok(code) => code & 0xff,
+            Err(_) => {
+                eprintln!("dcsh: exit: {}: numeric argument required", code);
+                2
+            }
+        },
+    };
+    context.exit(status);
+    status
+}
+
+/// `return [n]` stops the function that is running, with status `n` or else the status of the
+/// last command.
+fn return_from_function(context: &mut ExecContext, args: &[String]) -> i32 {
+    let status = match args.first() {
+        None => context.status(),
+        Some(code) => match code.parse::<i32>() {
+            Ok(code) => code & 0xff,
+            Err(_) => {
+                eprintln!("dcsh: return: {}: numeric argument required", code);
+                return 2;
+            }
+        },
+    };
+    if !context.return_from_function(status) {
+        eprintln!("dcsh: return: can only be used in a function");
+        return 1;
+    }
+    status
+}
+
+/// `local name[=value] ...` makes variables local to the function that is running.
+fn local(context: &mut ExecContext, args: &[String]) -> i32 {
+    let mut status = 0;
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            eprintln!("dcsh: local: {}: not a valid identifier", arg);
+            status = 1;
+            continue;
+        }
+        if !context.make_local(name) {
+            eprintln!("dcsh: local: can only be used in a function");
+            return 1;
+        }
+        if let Some(value) = value {
+            context.set_variable(name, value);
+        }
+    }
+    status
+}
+
+fn export(context: &mut ExecContext, args: &[String]) -> i32 {
+    if args.is_empty() {
+        for (name, value) in context.exported_variables() {
+            println!("export {}={}", name, value);
+        }
+        return 0;
+    }
+    let mut status = 0;
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            eprintln!("dcsh: export: {}: not a valid identifier", arg);
+            status = 1;
+            continue;
+        }
+        if let Some(value) = value {
+            context.set_variable(name, value);
+        }
+        context.export_variable(name);
+    }
+    status
+}
+
+/// Enables (`-o name`) or disables (`+o name`) shell options, or lists them with `set -o`.
+fn set(context: &mut ExecContext, args: &[String]) -> i32 {
+    if args.is_empty() || args == ["-o"] {
+        for (name, enabled) in context.options_mut().flags() {
+            println!("{}\t{}", name, on_off(*enabled));
+        }
+        return 0;
+    }
+    match context.options_mut().apply(args) {
+        Ok(count) if count == args.len() => 0,
+        Ok(count) => {
+            eprintln!("dcsh: set: {}: invalid option", args[count]);
+            2
+        }
+        Err(message) => {
+            eprintln!("dcsh: set: {}", message);
+            2
+        }
+    }
+}
+
+/// `shift [n]` drops the first `n` positional parameters, 1 by default.
+fn shift(context: &mut ExecContext, args: &[String]) -> i32 {
+    let count = match args.first().map(|arg| arg.parse::<usize>()) {
+        None => 1,
+        Some(Ok(count)) => count,
+        Some(Err(_)) => {
+            eprintln!("dcsh: shift: {}: numeric argument required", args[0]);
+            return 2;
+        }
+    };
+    if context.shift_arguments(count) {
+        0
+    } else {
+        1
+    }
+}
+
+/// `break [n]` stops the `n` innermost loops, 1 by default.
+fn break_loops(context: &mut ExecContext, args: &[String]) -> i32 {
+    match loop_count("break", args) {
+        Ok(count) => {
+            if !context.break_loops(count) {
+                eprintln!("dcsh: break: only meaningful in a loop");
+            }
+            0
+        }
+        Err(status) => status,
+    }
+}
+
+/// `continue [n]` skips to the next iteration of the `n`th innermost loop, 1 by default.
+fn continue_loops(context: &mut ExecContext, args: &[String]) -> i32 {
+    match loop_count("continue", args) {
+        Ok(count) => {
+            if !context.continue_loops(count) {
+                eprintln!("dcsh: continue: only meaningful in a loop");
+            }
+            0
+        }
+        Err(status) => status,
+    }
+}
+
+fn loop_count(builtin: &str, args: &[String]) -> Result<u32, i32> {
+    match args.first().map(|arg| arg.parse::<i64>()) {
+        None => Ok(1),
+        Some(Ok(count)) if count >= 1 => Ok(count.min(u32::MAX as i64) as u32),
+        Some(Ok(count)) => {
+            eprintln!("dcsh: {}: {}: loop count out of range", builtin, count);
+            Err(1)
+        }
+        Some(Err(_)) => {
+            eprintln!("dcsh: {}: {}: numeric argument required", builtin, args[0]);
+            Err(2)
+        }
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_export() {
+        let mut context = ExecContext::new();
+        context.set_variable("local", "1");
+        context.set_variable("shared", "2");
+        let args = ["shared".to_string(), "FOO=bar".to_string()];
+        assert_eq!(export(&mut context, &args), 0);
+        assert_eq!(
+            context.exported_variables(),
+            vec![("FOO", "bar"), ("shared", "2")]
+        );
+        assert_eq!(export(&mut context, &["1x-y".to_string()]), 1);
+    }
+}
@@ -1,151 +1,436 @@
+use lazy_static::lazy_static;
 use logos::{Lexer, Logos};
+use regex::Regex;
 use std::{
-    fs::File,
+    collections::VecDeque,
+    fmt,
     io::ErrorKind,
-    process::{ChildStdout, Command, Stdio},
+    io::{self, PipeReader, PipeWriter},
+    os::fd::RawFd,
+    process::Command,
 };
 
-use crate::exec::ExecContext;
+use crate::{
+    builtin,
+    exec::ExecContext,
+    expand::{unquote, word_length},
+    process::{self, exit_code, Process},
+    redirect::{Redirect, RedirectTarget, Redirections},
+};
+
+lazy_static! {
+    static ref RE_ASSIGNMENT_WORD: Regex =
+        Regex::new(r"\A([a-zA-Z_][a-zA-Z0-9_]*)=(.*)\z").unwrap();
+}
 
-#[derive(Logos, Debug, PartialEq, Eq)]
+#[derive(Logos, Debug, Clone, PartialEq, Eq)]
 #[logos(skip r"[ \t]*")]
+#[logos(skip r"#[^\n]*")]
 enum CommandToken {
     #[token("&&")]
     And,
     #[token("||")]
     Or,
+    #[token("&")]
+    Background,
     #[token(";")]
     Semicolon,
     #[token("|")]
     Pipe,
-    #[token("<")]
+    #[token("|&")]
+    PipeAll,
+    #[regex(r"[0-9]*<")]
     InputRedirect,
-    #[token(">")]
+    #[regex(r"[0-9]*<<")]
+    HereDoc,
+    /// `<<-`, which strips leading tabs from the here-document
+    #[regex(r"[0-9]*<<-")]
+    HereDocStrip,
+    #[regex(r"[0-9]*<<<")]
+    HereString,
+    #[regex(r"[0-9]*>")]
     OutputRedirect,
-    #[token("2>")]
-    StderrRedirect,
-    #[regex(r#""([^"]|\\")*""#)]
-    String,
-    #[regex(r"[^ \t;]+")]
+    #[regex(r"[0-9]*>>")]
+    AppendRedirect,
+    #[regex(r"[0-9]*[<>]&([0-9]+|-)")]
+    DuplicateRedirect,
+    #[token("&>")]
+    OutputAllRedirect,
+    #[token("&>>")]
+    AppendAllRedirect,
+    /// A word made of any mix of bare characters, backslash escapes, quoted strings, and command
+    /// substitutions. Substitutions can nest, so the rest of the word is scanned by hand. Quotes
+    /// and escapes are kept until the word is expanded.
+    #[regex(r"[^ \t\n;&|<>#]", lex_word)]
     Word,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Extends a word token from its first character to the end of the word.
+fn lex_word(lexer: &mut Lexer<CommandToken>) -> bool {
+    let start = lexer.span().start;
+    match word_length(&lexer.source()[start..]) {
+        Some(length) => {
+            lexer.bump(length - lexer.slice().len());
+            true
+        }
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct Invocation {
+    assignments: Vec<(String, String)>,
     executable: String,
     args: Vec<String>,
-    input_file: Option<String>,
-    output_file: Option<String>,
-    stderr_file: Option<String>,
+    redirects: Vec<Redirect>,
 }
 
 impl Invocation {
     fn new(executable: &str) -> Invocation {
         Invocation {
+            assignments: vec![],
             executable: executable.to_string(),
             args: vec![],
-            input_file: None,
-            output_file: None,
-            stderr_file: None,
+            redirects: vec![],
         }
     }
     #[allow(dead_code)]
+    fn assignment(mut self, variable: &str, value: &str) -> Invocation {
+        self.assignments
+            .push((variable.to_string(), value.to_string()));
+        self
+    }
+    #[allow(dead_code)]
     fn arg(mut self, arg: &str) -> Invocation {
         self.args.push(arg.to_string());
         self
     }
     #[allow(dead_code)]
-    fn input_file(mut self, input_file: &str) -> Invocation {
-        self.input_file = Some(input_file.to_string());
+    fn redirect(mut self, fd: RawFd, target: RedirectTarget) -> Invocation {
+        self.redirects.push(Redirect::new(fd, target));
         self
     }
     #[allow(dead_code)]
-    fn output_file(mut self, output_file: &str) -> Invocation {
-        self.output_file = Some(output_file.to_string());
-        self
+    fn input_file(self, input_file: &str) -> Invocation {
+        self.redirect(0, RedirectTarget::Input(input_file.to_string()))
     }
     #[allow(dead_code)]
-    fn stderr_file(mut self, stderr_file: &str) -> Invocation {
-        self.stderr_file = Some(stderr_file.to_string());
-        self
+    fn output_file(self, output_file: &str) -> Invocation {
+        self.redirect(1, RedirectTarget::Output(output_file.to_string()))
     }
-    fn command(&self) -> Command {
-        let mut command = Command::new(&self.executable);
-        command.args(&self.args);
-        if let Some(input_file) = &self.input_file {
-            let file = File::open(input_file).expect("failed to open input file");
-            command.stdin(file);
+    #[allow(dead_code)]
+    fn stderr_file(self, stderr_file: &str) -> Invocation {
+        self.redirect(2, RedirectTarget::Output(stderr_file.to_string()))
+    }
+    /// Substitutes variables and commands into every part of the invocation. Fails if any of the
+    /// expansions do, e.g. because of a `${name:?}` for an unset variable.
+    fn expand(&self, context: &mut ExecContext) -> Result<Invocation, ExecError> {
+        context.take_substitution_status();
+        context.take_expansion_error();
+        let assignments = self
+            .assignments
+            .iter()
+            .map(|(variable, value)| (variable.clone(), context.perform_substitution(value)))
+            .collect();
+        // Every word can expand to any number of fields, and the first field names the program
+        let mut words = vec![];
+        if !self.executable.is_empty() {
+            words.extend(context.expand_word(&self.executable));
         }
-        if let Some(output_file) = &self.output_file {
-            let file = File::create(output_file).expect("failed to open output file");
-            command.stdout(file);
+        for arg in &self.args {
+            words.extend(context.expand_word(arg));
         }
-        if let Some(stderr_file) = &self.stderr_file {
-            let file = File::create(stderr_file).expect("failed to open stderr file");
-            command.stderr(file);
+        let mut words = words.into_iter();
+        let executable = words.next().unwrap_or_default();
+        let args = words.collect();
+        let redirects = self
+            .redirects
+            .iter()
+            .map(|redirect| {
+                let target = match &redirect.target {
+                    RedirectTarget::Input(path) => {
+                        RedirectTarget::Input(context.perform_substitution(path))
+                    }
+                    RedirectTarget::Output(path) => {
+                        RedirectTarget::Output(context.perform_substitution(path))
+                    }
+                    RedirectTarget::Append(path) => {
+                        RedirectTarget::Append(context.perform_substitution(path))
+                    }
+                    RedirectTarget::HereDoc { body, expand: true } => RedirectTarget::HereDoc {
+                        body: context.perform_heredoc_substitution(body),
+                        expand: false,
+                    },
+                    RedirectTarget::HereString(word) => RedirectTarget::HereDoc {
+                        body: format!("{}\n", context.perform_substitution(word)),
+                        expand: false,
+                    },
+                    target => target.clone(),
+                };
+                Redirect::new(redirect.fd, target)
+            })
+            .collect();
+        if let Some(message) = context.take_expansion_error() {
+            context.abort_on_expansion_error();
+            return Err(ExecError::Expansion(message));
         }
+        let invocation = Invocation {
+            assignments,
+            executable,
+            args,
+            redirects,
+        };
+        if context.options().xtrace {
+            let prefix = context.get_variable("PS4").unwrap_or("+ ");
+            eprintln!("{}{}", prefix, invocation.trace());
+        }
+        Ok(invocation)
+    }
+    /// The words of the invocation before they are expanded, without any redirections.
+    fn words(&self) -> String {
+        let assignments = self
+            .assignments
+            .iter()
+            .map(|(variable, value)| format!("{}={}", variable, value));
+        let words = std::iter::once(&self.executable)
+            .filter(|executable| !executable.is_empty())
+            .chain(&self.args)
+            .cloned();
+        assignments.chain(words).collect::<Vec<_>>().join(" ")
+    }
+    /// The invocation as `xtrace` prints it, quoting words so that they could be run again.
+    fn trace(&self) -> String {
+        let assignments = self
+            .assignments
+            .iter()
+            .map(|(variable, value)| format!("{}={}", variable, quote(value)));
+        let words = std::iter::once(&self.executable)
+            .filter(|executable| !executable.is_empty())
+            .chain(&self.args)
+            .map(|word| quote(word));
+        assignments.chain(words).collect::<Vec<_>>().join(" ")
+    }
+    /// Whether the invocation is handled by the shell itself rather than an external program.
+    fn runs_in_shell(&self, context: &ExecContext) -> bool {
+        self.executable.is_empty()
+            || context.function(&self.executable).is_some()
+            || builtin::lookup(&self.executable).is_some()
+    }
+    /// Builds the command for an external program. Redirections are not included, since they are
+    /// applied to the shell's own file descriptors for the child to inherit.
+    fn command(&self, context: &ExecContext) -> Command {
+        let mut command = Command::new(&self.executable);
+        command.args(&self.args);
+        command.env_clear();
+        command.envs(context.exported_variables());
+        command.envs(self.assignments.iter().map(|(k, v)| (k, v)));
         command
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum InvocationChain {
     And,
     Or,
     Semicolon,
     Pipe,
+    /// `|&`, which pipes both stdout and stderr
+    PipeAll,
+    Background,
+}
+
+/// A parsed command line. `|` binds tighter than `&&` and `||`, which bind tighter than `;` and
+/// `&`.
+#[derive(Debug, PartialEq, Eq)]
+enum CommandTree {
+    Pipeline(Vec<Invocation>),
+    And(Box<CommandTree>, Box<CommandTree>),
+    Or(Box<CommandTree>, Box<CommandTree>),
+    Sequence(Box<CommandTree>, Box<CommandTree>),
+    Background(Box<CommandTree>),
 }
 
 #[derive(Debug, PartialEq, Eq)]
-// TODO rename
 pub enum SyntaxError {
     ExpectedString,
     InvalidSyntax,
+}
+
+/// An error that prevents an invocation from running at all.
+#[derive(Debug)]
+pub enum ExecError {
     CommandNotFound(String),
-    IOError,
+    /// The program exists but couldn't be started, e.g. because it isn't executable.
+    Spawn(String, io::Error),
+    /// A file named in a redirection couldn't be opened.
+    Redirect(String, io::Error),
+    /// One of the invocation's words couldn't be expanded.
+    Expansion(String),
 }
 
-fn parse_string(
-    context: &ExecContext,
-    lexer: &mut Lexer<CommandToken>,
-) -> Result<String, SyntaxError> {
-    let token = lexer.next();
-    if let Some(Ok(CommandToken::Word)) = token {
-        Ok(context.perform_substitution(lexer.slice()))
-    } else if let Some(Ok(CommandToken::String)) = token {
-        let slice = lexer.slice();
-        Ok(context.perform_substitution(&slice[1..slice.len() - 1]))
+impl ExecError {
+    /// The exit status to report in place of the invocation's own.
+    fn status(&self) -> i32 {
+        match self {
+            ExecError::CommandNotFound(_) => 127,
+            ExecError::Spawn(_, _) => 126,
+            ExecError::Redirect(_, _) | ExecError::Expansion(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::CommandNotFound(command) => write!(f, "command not found: {}", command),
+            ExecError::Expansion(message) => write!(f, "{}", message),
+            ExecError::Spawn(command, err) | ExecError::Redirect(command, err) => {
+                write!(f, "{}: {}", command, describe_io_error(err))
+            }
+        }
+    }
+}
+
+/// Quotes a word in single quotes if it has any characters that the shell would treat specially.
+fn quote(word: &str) -> String {
+    let plain = |c: char| c.is_alphanumeric() || "-_./=:,+@%^".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+fn parse_string(lexer: &mut Lexer<CommandToken>) -> Result<String, SyntaxError> {
+    if let Some(Ok(CommandToken::Word)) = lexer.next() {
+        Ok(lexer.slice().to_string())
     } else {
         Err(SyntaxError::ExpectedString)
     }
 }
 
+/// Reads the file descriptor number at the start of a redirection operator like `2>`.
+fn parse_fd(operator: &str, default: RawFd) -> RawFd {
+    let digits = operator.trim_end_matches(|c: char| !c.is_ascii_digit());
+    digits.parse().unwrap_or(default)
+}
+
+/// Finds the here-documents started on a command line, and reads their bodies from the lines
+/// that follow it. Returns the bodies in order, and how much of `following` they took up.
+pub fn read_heredocs(line: &str, following: &str) -> (VecDeque<String>, usize) {
+    let mut delimiters = vec![];
+    let mut lexer = CommandToken::lexer(line);
+    while let Some(token) = lexer.next() {
+        let strip_tabs = match token {
+            Ok(CommandToken::HereDoc) => false,
+            Ok(CommandToken::HereDocStrip) => true,
+            _ => continue,
+        };
+        if let Ok(delimiter) = parse_string(&mut lexer) {
+            delimiters.push((unquote(&delimiter), strip_tabs));
+        }
+    }
+    let mut bodies = VecDeque::new();
+    let mut consumed = 0;
+    for (delimiter, strip_tabs) in delimiters {
+        let mut body = String::new();
+        for line in following[consumed..].split_inclusive('\n') {
+            consumed += line.len();
+            let line = if strip_tabs {
+                line.trim_start_matches('\t')
+            } else {
+                line
+            };
+            if line.trim_end_matches('\n') == delimiter {
+                break;
+            }
+            body.push_str(line);
+        }
+        bodies.push_back(body);
+    }
+    (bodies, consumed)
+}
+
 fn parse_single_invocation(
-    context: &ExecContext,
     lexer: &mut Lexer<CommandToken>,
+    heredocs: &mut VecDeque<String>,
 ) -> Result<(Invocation, Option<InvocationChain>), SyntaxError> {
-    let executable = parse_string(context, lexer)?;
-    let mut invocation = Invocation::new(&executable);
+    let mut invocation = Invocation::new("");
     let mut token = lexer.next();
+    // Leading NAME=value words only apply to this invocation
+    while let Some(Ok(CommandToken::Word)) = token {
+        if let Some(captures) = RE_ASSIGNMENT_WORD.captures(lexer.slice()) {
+            let assignment = (captures[1].to_string(), captures[2].to_string());
+            invocation.assignments.push(assignment);
+            token = lexer.next();
+        } else {
+            break;
+        }
+    }
+    match token {
+        Some(Ok(CommandToken::Word)) => {
+            invocation.executable = lexer.slice().to_string();
+            token = lexer.next();
+        }
+        _ if !invocation.assignments.is_empty() => {}
+        _ => return Err(SyntaxError::ExpectedString),
+    }
     loop {
         match token {
-            Some(Ok(CommandToken::Word)) => invocation
-                .args
-                .push(context.perform_substitution(lexer.slice())),
-            Some(Ok(CommandToken::String)) => {
-                let slice = lexer.slice();
-                invocation
-                    .args
-                    .push(context.perform_substitution(&slice[1..slice.len() - 1]))
-            }
+            Some(Ok(CommandToken::Word)) => invocation.args.push(lexer.slice().to_string()),
             Some(Ok(CommandToken::InputRedirect)) => {
-                invocation.input_file = Some(parse_string(context, lexer)?)
+                let fd = parse_fd(lexer.slice(), 0);
+                let target = RedirectTarget::Input(parse_string(lexer)?);
+                invocation.redirects.push(Redirect::new(fd, target));
+            }
+            Some(Ok(CommandToken::HereDoc | CommandToken::HereDocStrip)) => {
+                let fd = parse_fd(lexer.slice(), 0);
+                let delimiter = parse_string(lexer)?;
+                let target = RedirectTarget::HereDoc {
+                    body: heredocs.pop_front().unwrap_or_default(),
+                    // Quoting any part of the delimiter turns off expansion in the body
+                    expand: unquote(&delimiter) == delimiter,
+                };
+                invocation.redirects.push(Redirect::new(fd, target));
+            }
+            Some(Ok(CommandToken::HereString)) => {
+                let fd = parse_fd(lexer.slice(), 0);
+                let target = RedirectTarget::HereString(parse_string(lexer)?);
+                invocation.redirects.push(Redirect::new(fd, target));
             }
             Some(Ok(CommandToken::OutputRedirect)) => {
-                invocation.output_file = Some(parse_string(context, lexer)?)
+                let fd = parse_fd(lexer.slice(), 1);
+                let target = RedirectTarget::Output(parse_string(lexer)?);
+                invocation.redirects.push(Redirect::new(fd, target));
+            }
+            Some(Ok(CommandToken::AppendRedirect)) => {
+                let fd = parse_fd(lexer.slice(), 1);
+                let target = RedirectTarget::Append(parse_string(lexer)?);
+                invocation.redirects.push(Redirect::new(fd, target));
             }
-            Some(Ok(CommandToken::StderrRedirect)) => {
-                invocation.stderr_file = Some(parse_string(context, lexer)?)
+            Some(Ok(CommandToken::DuplicateRedirect)) => {
+                let slice = lexer.slice();
+                let (fd, source) = slice.split_once('&').unwrap();
+                let fd = parse_fd(fd, if fd.ends_with('<') { 0 } else { 1 });
+                let target = match source.parse() {
+                    Ok(source) => RedirectTarget::Duplicate(source),
+                    Err(_) => RedirectTarget::Close,
+                };
+                invocation.redirects.push(Redirect::new(fd, target));
+            }
+            Some(Ok(CommandToken::OutputAllRedirect)) => {
+                let target = RedirectTarget::Output(parse_string(lexer)?);
+                invocation.redirects.push(Redirect::new(1, target));
+                invocation
+                    .redirects
+                    .push(Redirect::new(2, RedirectTarget::Duplicate(1)));
+            }
+            Some(Ok(CommandToken::AppendAllRedirect)) => {
+                let target = RedirectTarget::Append(parse_string(lexer)?);
+                invocation.redirects.push(Redirect::new(1, target));
+                invocation
+                    .redirects
+                    .push(Redirect::new(2, RedirectTarget::Duplicate(1)));
             }
             Some(Ok(CommandToken::And)) => return Ok((invocation, Some(InvocationChain::And))),
             Some(Ok(CommandToken::Or)) => return Ok((invocation, Some(InvocationChain::Or))),
@@ -153,6 +438,16 @@ fn parse_single_invocation(
                 return Ok((invocation, Some(InvocationChain::Semicolon)))
             }
             Some(Ok(CommandToken::Pipe)) => return Ok((invocation, Some(InvocationChain::Pipe))),
+            Some(Ok(CommandToken::PipeAll)) => {
+                // Like 2>&1, but after any other redirections
+                invocation
+                    .redirects
+                    .push(Redirect::new(2, RedirectTarget::Duplicate(1)));
+                return Ok((invocation, Some(InvocationChain::PipeAll)));
+            }
+            Some(Ok(CommandToken::Background)) => {
+                return Ok((invocation, Some(InvocationChain::Background)))
+            }
             Some(Err(_)) => return Err(SyntaxError::InvalidSyntax),
             None => return Ok((invocation, None)),
         }
@@ -160,149 +455,610 @@ fn parse_single_invocation(
     }
 }
 
-fn parse_command(
-    context: &ExecContext,
-    source: &str,
-) -> Result<Vec<(Invocation, Option<InvocationChain>)>, SyntaxError> {
-    let mut lexer = CommandToken::lexer(source);
-    let mut invocations = vec![];
+/// Parses a command line, which may be followed by the bodies of its here-documents.
+fn parse_command(source: &str) -> Result<CommandTree, SyntaxError> {
+    let (line, following) = source.split_once('\n').unwrap_or((source, ""));
+    let (mut heredocs, _) = read_heredocs(line, following);
+    let mut lexer = CommandToken::lexer(line);
+    let mut list: Option<CommandTree> = None;
+    let mut and_or: Option<(CommandTree, InvocationChain)> = None;
+    let mut pipeline = vec![];
     loop {
-        let (invocation, chain) = parse_single_invocation(context, &mut lexer)?;
-        if chain.is_none() {
-            invocations.push((invocation, chain));
-            break;
+        let (invocation, chain) = parse_single_invocation(&mut lexer, &mut heredocs)?;
+        pipeline.push(invocation);
+        if let Some(InvocationChain::Pipe | InvocationChain::PipeAll) = chain {
+            continue;
+        }
+        let mut tree = CommandTree::Pipeline(std::mem::take(&mut pipeline));
+        // && and || have equal precedence and associate to the left
+        tree = match and_or.take() {
+            Some((left, InvocationChain::And)) => CommandTree::And(Box::new(left), Box::new(tree)),
+            Some((left, _)) => CommandTree::Or(Box::new(left), Box::new(tree)),
+            None => tree,
+        };
+        match chain {
+            Some(chain @ (InvocationChain::And | InvocationChain::Or)) => {
+                and_or = Some((tree, chain));
+            }
+            _ => {
+                if chain == Some(InvocationChain::Background) {
+                    tree = CommandTree::Background(Box::new(tree));
+                }
+                list = Some(match list.take() {
+                    Some(left) => CommandTree::Sequence(Box::new(left), Box::new(tree)),
+                    None => tree,
+                });
+                // A trailing `;` or `&` may be followed by nothing but blanks and a comment
+                if chain.is_none() || lexer.clone().next().is_none() {
+                    return Ok(list.unwrap());
+                }
+            }
         }
-        invocations.push((invocation, chain));
     }
-    Ok(invocations)
 }
 
-fn handle_err<T>(invocation: Invocation, r: std::io::Result<T>) -> Result<T, SyntaxError> {
+/// Formats an IO error the way other shells do, without the trailing `(os error N)`.
+pub fn describe_io_error(err: &io::Error) -> String {
+    let description = err.to_string();
+    match description.find(" (os error ") {
+        Some(index) => description[0..index].to_string(),
+        None => description,
+    }
+}
+
+fn handle_err<T>(invocation: Invocation, r: io::Result<T>) -> Result<T, ExecError> {
     r.map_err(|err| {
         if err.kind() == ErrorKind::NotFound {
-            SyntaxError::CommandNotFound(invocation.executable)
+            ExecError::CommandNotFound(invocation.executable)
         } else {
-            SyntaxError::IOError
+            ExecError::Spawn(invocation.executable, err)
         }
     })
 }
 
-pub fn exec_command(context: &ExecContext, command: &str) -> Result<i32, SyntaxError> {
-    let invocations = parse_command(context, command)?;
-    let mut previous_stdout: Option<ChildStdout> = None;
-    for (invocation, chain) in invocations {
-        let mut command = invocation.command();
-        if let Some(stdout) = previous_stdout {
-            command.stdin(Stdio::from(stdout));
+/// Reports an error that prevented an invocation from running, returning the status to use
+/// in its place.
+fn error_status(err: ExecError) -> i32 {
+    eprintln!("dcsh: {}", err);
+    err.status()
+}
+
+/// Runs an invocation whose redirections have already been applied, preferring functions and then
+/// builtins over external programs.
+fn run_redirected(context: &mut ExecContext, invocation: Invocation) -> Result<i32, ExecError> {
+    if invocation.executable.is_empty() {
+        // Assignments without a command set shell variables
+        for (variable, value) in &invocation.assignments {
+            context.set_variable(variable, value);
         }
-        previous_stdout = None;
-        // TODO join stdout and stderr when piping
-        // TODO join < and piped stdin
-        match chain {
-            Some(InvocationChain::And) => {
-                let status = handle_err(invocation, command.status())?;
-                if !status.success() {
-                    return Ok(status.code().unwrap());
+        return Ok(context.take_substitution_status().unwrap_or(0));
+    }
+    let assignments = &invocation.assignments;
+    if let Some(function) = context.function(&invocation.executable).cloned() {
+        return Ok(context.with_assignments(assignments, |context| {
+            context.call_function(function, &invocation.args)
+        }));
+    }
+    if let Some(builtin) = builtin::lookup(&invocation.executable) {
+        return Ok(
+            context.with_assignments(assignments, |context| builtin(context, &invocation.args))
+        );
+    }
+    let mut command = invocation.command(context);
+    Ok(exit_code(handle_err(invocation, command.status())?))
+}
+
+/// Runs an invocation in the foreground.
+fn run_foreground(context: &mut ExecContext, invocation: Invocation) -> Result<i32, ExecError> {
+    let redirections = Redirections::apply_all(&invocation.redirects)?;
+    let result = run_redirected(context, invocation);
+    redirections.restore();
+    result
+}
+
+/// Starts an external program without waiting for it to finish. Its own redirections are applied
+/// after any that are already in place, like the pipes of a pipeline.
+fn spawn(context: &ExecContext, invocation: Invocation, mut redirections: Redirections) -> Process {
+    let mut result = Ok(());
+    for redirect in &invocation.redirects {
+        result = redirections.apply(redirect);
+        if result.is_err() {
+            break;
+        }
+    }
+    let result =
+        result.and_then(|_| handle_err(invocation.clone(), invocation.command(context).spawn()));
+    redirections.restore();
+    match result {
+        Ok(child) => Process::Spawned(child),
+        Err(err) => Process::Finished(error_status(err)),
+    }
+}
+
+/// Points stdin and stdout at the pipes connecting a pipeline stage to its neighbours.
+fn connect_pipes(
+    stdin: Option<PipeReader>,
+    stdout: Option<PipeWriter>,
+) -> Result<Redirections, ExecError> {
+    let mut redirections = Redirections::new();
+    let mut result = Ok(());
+    if let Some(stdin) = stdin {
+        result = redirections.connect(0, stdin);
+    }
+    if let (Some(stdout), Ok(())) = (stdout, &result) {
+        result = redirections.connect(1, stdout);
+    }
+    match result {
+        Ok(()) => Ok(redirections),
+        Err(err) => {
+            redirections.restore();
+            Err(err)
+        }
+    }
+}
+
+/// Starts one stage of a pipeline, reading from `stdin` and writing to `stdout` unless the
+/// invocation redirects them elsewhere. Builtins run in a forked copy of the shell.
+fn start_stage(
+    context: &mut ExecContext,
+    invocation: Invocation,
+    stdin: Option<PipeReader>,
+    stdout: Option<PipeWriter>,
+) -> Process {
+    let invocation = match invocation.expand(context) {
+        Ok(invocation) => invocation,
+        Err(err) => return Process::Finished(error_status(err)),
+    };
+    if invocation.runs_in_shell(context) {
+        let forked = process::fork(|| {
+            let result =
+                connect_pipes(stdin, stdout).and_then(|_| run_foreground(context, invocation));
+            result.unwrap_or_else(error_status)
+        });
+        return forked.unwrap_or_else(|err| {
+            eprintln!("dcsh: fork: {}", describe_io_error(&err));
+            Process::Finished(1)
+        });
+    }
+    match connect_pipes(stdin, stdout) {
+        Ok(redirections) => spawn(context, invocation, redirections),
+        Err(err) => Process::Finished(error_status(err)),
+    }
+}
+
+/// Runs a pipeline in the foreground and waits for every invocation in it to finish.
+fn exec_pipeline(context: &mut ExecContext, invocations: Vec<Invocation>) -> i32 {
+    let words: Vec<String> = invocations.iter().map(Invocation::words).collect();
+    context.set_last_command(words.join(" | "));
+    if invocations.len() == 1 {
+        let invocation = invocations.into_iter().next().unwrap();
+        let status = invocation
+            .expand(context)
+            .and_then(|invocation| run_foreground(context, invocation))
+            .unwrap_or_else(error_status);
+        return context.set_pipeline_status(vec![status]);
+    }
+    let mut processes = vec![];
+    let mut previous_stdout: Option<PipeReader> = None;
+    let last = invocations.len() - 1;
+    for (index, invocation) in invocations.into_iter().enumerate() {
+        let stdin = previous_stdout.take();
+        let mut stdout = None;
+        if index != last {
+            match io::pipe() {
+                Ok((reader, writer)) => {
+                    previous_stdout = Some(reader);
+                    stdout = Some(writer);
+                }
+                Err(err) => {
+                    eprintln!("dcsh: pipe: {}", describe_io_error(&err));
+                    processes.push(Process::Finished(1));
+                    break;
                 }
             }
-            Some(InvocationChain::Or) => {
-                let status = handle_err(invocation, command.status())?;
-                if status.success() {
-                    return Ok(status.code().unwrap());
+        }
+        processes.push(start_stage(context, invocation, stdin, stdout));
+    }
+    let statuses = processes.into_iter().map(Process::wait).collect();
+    context.set_pipeline_status(statuses)
+}
+
+/// Starts a command line without waiting for it to finish, recording its PID as `$!`. Anything
+/// more than a single external program runs in a forked subshell.
+fn exec_background(context: &mut ExecContext, tree: CommandTree) -> i32 {
+    let result = match tree {
+        CommandTree::Pipeline(invocations) if invocations.len() == 1 => {
+            let invocation = match invocations.into_iter().next().unwrap().expand(context) {
+                Ok(invocation) => invocation,
+                Err(err) => {
+                    let status = error_status(err);
+                    context.set_status(status);
+                    return status;
                 }
+            };
+            if invocation.runs_in_shell(context) {
+                process::fork(|| run_foreground(context, invocation).unwrap_or_else(error_status))
+            } else {
+                Ok(spawn(context, invocation, Redirections::new()))
             }
-            Some(InvocationChain::Pipe) => {
-                command.stdout(Stdio::piped());
-                let child = handle_err(invocation, command.spawn())?;
-                previous_stdout = child.stdout;
+        }
+        tree => process::fork(|| exec_tree(context, tree)),
+    };
+    match result {
+        Ok(Process::Finished(status)) => {
+            context.set_status(status);
+            status
+        }
+        Ok(job) => {
+            context.add_job(job);
+            context.set_status(0);
+            0
+        }
+        Err(err) => {
+            eprintln!("dcsh: fork: {}", describe_io_error(&err));
+            context.set_status(1);
+            1
+        }
+    }
+}
+
+fn exec_tree(context: &mut ExecContext, tree: CommandTree) -> i32 {
+    match tree {
+        CommandTree::Pipeline(invocations) => exec_pipeline(context, invocations),
+        CommandTree::And(left, right) => {
+            let status = context.run_as_condition(|context| exec_tree(context, *left));
+            if status == 0 && !context.is_interrupted() {
+                exec_tree(context, *right)
+            } else {
+                context.exempt_failure();
+                status
             }
-            Some(InvocationChain::Semicolon) => {
-                command.status().expect("failed to spawn process");
+        }
+        CommandTree::Or(left, right) => {
+            let status = context.run_as_condition(|context| exec_tree(context, *left));
+            if status != 0 && !context.is_interrupted() {
+                exec_tree(context, *right)
+            } else {
+                status
             }
-            None => {
-                return Ok(handle_err(invocation, command.status())?.code().unwrap());
+        }
+        CommandTree::Sequence(left, right) => {
+            let status = exec_list(context, *left);
+            if context.is_interrupted() {
+                status
+            } else {
+                exec_list(context, *right)
             }
         }
+        CommandTree::Background(tree) => exec_background(context, *tree),
     }
-    Ok(0)
+}
+
+/// Runs an and-or list, then checks whether it failed, e.g. to stop a `try` block.
+fn exec_list(context: &mut ExecContext, tree: CommandTree) -> i32 {
+    let status = exec_tree(context, tree);
+    context.check_failure(status);
+    status
+}
+
+pub fn exec_command(context: &mut ExecContext, command: &str) -> Result<i32, SyntaxError> {
+    let tree = parse_command(command)?;
+    Ok(exec_list(context, tree))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn pipeline(invocations: Vec<Invocation>) -> CommandTree {
+        CommandTree::Pipeline(invocations)
+    }
+
+    fn single(invocation: Invocation) -> CommandTree {
+        CommandTree::Pipeline(vec![invocation])
+    }
+
     #[test]
     fn test_parse_command() {
         let mut context = ExecContext::new();
+        assert_eq!(parse_command("ls"), Ok(single(Invocation::new("ls"))));
         assert_eq!(
-            parse_command(&context, "ls"),
-            Ok(vec![(Invocation::new("ls"), None)])
+            parse_command("ls -al"),
+            Ok(single(Invocation::new("ls").arg("-al")))
         );
         assert_eq!(
-            parse_command(&context, "ls -al"),
-            Ok(vec![(Invocation::new("ls").arg("-al"), None)])
+            parse_command("ls -al | grep foo"),
+            Ok(pipeline(vec![
+                Invocation::new("ls").arg("-al"),
+                Invocation::new("grep").arg("foo")
+            ]))
         );
         assert_eq!(
-            parse_command(&context, "ls -al | grep foo"),
-            Ok(vec![
-                (
+            parse_command("ls -al | grep foo || touch foo"),
+            Ok(CommandTree::Or(
+                Box::new(pipeline(vec![
                     Invocation::new("ls").arg("-al"),
-                    Some(InvocationChain::Pipe)
-                ),
-                (Invocation::new("grep").arg("foo"), None)
-            ])
+                    Invocation::new("grep").arg("foo")
+                ])),
+                Box::new(single(Invocation::new("touch").arg("foo")))
+            ))
         );
         assert_eq!(
-            parse_command(&context, "ls -al | grep foo || touch foo"),
-            Ok(vec![
-                (
-                    Invocation::new("ls").arg("-al"),
-                    Some(InvocationChain::Pipe)
-                ),
-                (
-                    Invocation::new("grep").arg("foo"),
-                    Some(InvocationChain::Or)
-                ),
-                (Invocation::new("touch").arg("foo"), None)
-            ])
-        );
-        assert_eq!(
-            parse_command(&context, "ls -al && pwd"),
-            Ok(vec![
-                (Invocation::new("ls").arg("-al"), Some(InvocationChain::And)),
-                (Invocation::new("pwd"), None)
-            ])
-        );
-        assert_eq!(
-            parse_command(&context, "ls -al; pwd"),
-            Ok(vec![
-                (
-                    Invocation::new("ls").arg("-al"),
-                    Some(InvocationChain::Semicolon)
-                ),
-                (Invocation::new("pwd"), None)
-            ])
+            parse_command("ls -al && pwd"),
+            Ok(CommandTree::And(
+                Box::new(single(Invocation::new("ls").arg("-al"))),
+                Box::new(single(Invocation::new("pwd")))
+            ))
+        );
+        assert_eq!(
+            parse_command("ls -al; pwd"),
+            Ok(CommandTree::Sequence(
+                Box::new(single(Invocation::new("ls").arg("-al"))),
+                Box::new(single(Invocation::new("pwd")))
+            ))
+        );
+        assert_eq!(
+            parse_command("cat < foo"),
+            Ok(single(Invocation::new("cat").input_file("foo")))
+        );
+        assert_eq!(
+            parse_command("cat > foo"),
+            Ok(single(Invocation::new("cat").output_file("foo")))
+        );
+        assert_eq!(
+            parse_command("cat 2> foo"),
+            Ok(single(Invocation::new("cat").stderr_file("foo")))
+        );
+        assert_eq!(
+            parse_command("FOO=1 BAR= make -j"),
+            Ok(single(
+                Invocation::new("make")
+                    .assignment("FOO", "1")
+                    .assignment("BAR", "")
+                    .arg("-j")
+            ))
+        );
+        assert_eq!(
+            parse_command("FOO=1"),
+            Ok(single(Invocation::new("").assignment("FOO", "1")))
+        );
+        assert_eq!(
+            parse_command("make FOO=1"),
+            Ok(single(Invocation::new("make").arg("FOO=1")))
+        );
+        context.set_variable("eecchhoo", "echo");
+        assert_eq!(
+            parse_command("$eecchhoo foo"),
+            Ok(single(Invocation::new("$eecchhoo").arg("foo")))
+        );
+        let Ok(CommandTree::Pipeline(mut invocations)) = parse_command("${eecchhoo} foo") else {
+            panic!("expected a pipeline");
+        };
+        assert_eq!(
+            invocations.remove(0).expand(&mut context).unwrap(),
+            Invocation::new("echo").arg("foo")
+        );
+        assert_eq!(
+            parse_command("sleep 1 & ls"),
+            Ok(CommandTree::Sequence(
+                Box::new(CommandTree::Background(Box::new(single(
+                    Invocation::new("sleep").arg("1")
+                )))),
+                Box::new(single(Invocation::new("ls")))
+            ))
+        );
+        assert_eq!(
+            parse_command("sleep 1 &"),
+            Ok(CommandTree::Background(Box::new(single(
+                Invocation::new("sleep").arg("1")
+            ))))
+        );
+    }
+
+    #[test]
+    fn test_parse_quoted_words() {
+        assert_eq!(
+            parse_command(r#"echo foo"bar"'baz' "a b" 'c;d' e\ f\;g "h\"i" '#x' #comment"#),
+            Ok(single(
+                Invocation::new("echo")
+                    .arg(r#"foo"bar"'baz'"#)
+                    .arg(r#""a b""#)
+                    .arg("'c;d'")
+                    .arg(r"e\ f\;g")
+                    .arg(r#""h\"i""#)
+                    .arg("'#x'")
+            ))
+        );
+        assert_eq!(
+            parse_command("ls|wc>out"),
+            Ok(pipeline(vec![
+                Invocation::new("ls"),
+                Invocation::new("wc").output_file("out")
+            ]))
+        );
+        assert_eq!(parse_command("echo \"foo"), Err(SyntaxError::InvalidSyntax));
+        assert_eq!(
+            parse_command("echo a; # comment"),
+            Ok(single(Invocation::new("echo").arg("a")))
+        );
+        assert_eq!(
+            parse_command("echo a & # comment"),
+            Ok(CommandTree::Background(Box::new(single(
+                Invocation::new("echo").arg("a")
+            ))))
+        );
+        let mut context = ExecContext::new();
+        let Ok(CommandTree::Pipeline(mut invocations)) = parse_command(r#"FOO="a b" echo '$HOME'"#)
+        else {
+            panic!("expected a pipeline");
+        };
+        assert_eq!(
+            invocations.remove(0).expand(&mut context).unwrap(),
+            Invocation::new("echo")
+                .assignment("FOO", "a b")
+                .arg("$HOME")
         );
+    }
+
+    #[test]
+    fn test_parse_redirects() {
         assert_eq!(
-            parse_command(&context, "cat < foo"),
-            Ok(vec![(Invocation::new("cat").input_file("foo"), None)])
+            parse_command("cmd >> log 2>>err 3> three 4<four <&3 5>&- >&2"),
+            Ok(single(
+                Invocation::new("cmd")
+                    .redirect(1, RedirectTarget::Append("log".to_string()))
+                    .redirect(2, RedirectTarget::Append("err".to_string()))
+                    .redirect(3, RedirectTarget::Output("three".to_string()))
+                    .redirect(4, RedirectTarget::Input("four".to_string()))
+                    .redirect(0, RedirectTarget::Duplicate(3))
+                    .redirect(5, RedirectTarget::Close)
+                    .redirect(1, RedirectTarget::Duplicate(2))
+            ))
         );
         assert_eq!(
-            parse_command(&context, "cat > foo"),
-            Ok(vec![(Invocation::new("cat").output_file("foo"), None)])
+            parse_command("cmd > log 2>&1"),
+            Ok(single(
+                Invocation::new("cmd")
+                    .output_file("log")
+                    .redirect(2, RedirectTarget::Duplicate(1))
+            ))
         );
         assert_eq!(
-            parse_command(&context, "cat 2> foo"),
-            Ok(vec![(Invocation::new("cat").stderr_file("foo"), None)])
+            parse_command("cmd &> log; cmd &>> log"),
+            Ok(CommandTree::Sequence(
+                Box::new(single(
+                    Invocation::new("cmd")
+                        .output_file("log")
+                        .redirect(2, RedirectTarget::Duplicate(1))
+                )),
+                Box::new(single(
+                    Invocation::new("cmd")
+                        .redirect(1, RedirectTarget::Append("log".to_string()))
+                        .redirect(2, RedirectTarget::Duplicate(1))
+                ))
+            ))
         );
-        context.exec_assignment("eecchhoo".to_string(), "echo".to_string());
         assert_eq!(
-            parse_command(&context, "$eecchhoo foo"),
-            Ok(vec![(Invocation::new("echo").arg("foo"), None)])
+            parse_command("cmd 2>/dev/null |& cat"),
+            Ok(pipeline(vec![
+                Invocation::new("cmd")
+                    .stderr_file("/dev/null")
+                    .redirect(2, RedirectTarget::Duplicate(1)),
+                Invocation::new("cat")
+            ]))
         );
         assert_eq!(
-            parse_command(&context, "${eecchhoo} foo"),
-            Ok(vec![(Invocation::new("echo").arg("foo"), None)])
+            parse_command("echo 2 >x"),
+            Ok(single(Invocation::new("echo").arg("2").output_file("x")))
+        );
+    }
+
+    #[test]
+    fn test_parse_heredocs() {
+        let mut context = ExecContext::new();
+        context.set_variable("x", "1");
+        let source = "cat <<EOF | wc 3<<-'END' <<< \"$x y\"\n$x\n  EOF\nEOF\n\t\t$x\n\tEND";
+        assert_eq!(
+            read_heredocs(
+                "cat <<EOF | wc 3<<-'END'",
+                "$x\n  EOF\nEOF\n\t\t$x\n\tEND\nls"
+            )
+            .1,
+            23
         );
+        let Ok(CommandTree::Pipeline(invocations)) = parse_command(source) else {
+            panic!("expected a pipeline");
+        };
+        let heredoc = |body: &str, expand| RedirectTarget::HereDoc {
+            body: body.to_string(),
+            expand,
+        };
+        assert_eq!(
+            invocations,
+            vec![
+                Invocation::new("cat").redirect(0, heredoc("$x\n  EOF\n", true)),
+                Invocation::new("wc")
+                    .redirect(3, heredoc("$x\n", false))
+                    .redirect(0, RedirectTarget::HereString("\"$x y\"".to_string()))
+            ]
+        );
+        assert_eq!(
+            invocations[0].expand(&mut context).unwrap(),
+            Invocation::new("cat").redirect(0, heredoc("1\n  EOF\n", false))
+        );
+        assert_eq!(
+            invocations[1].expand(&mut context).unwrap(),
+            Invocation::new("wc")
+                .redirect(3, heredoc("$x\n", false))
+                .redirect(0, heredoc("1 y\n", false))
+        );
+    }
+
+    #[test]
+    fn test_redirect_errors() {
+        let err = Redirections::apply_all(&[Redirect::new(
+            0,
+            RedirectTarget::Input("/nonexistent/missing".to_string()),
+        )])
+        .err()
+        .unwrap();
+        assert!(matches!(err, ExecError::Redirect(_, _)));
+        assert_eq!(err.status(), 1);
+        assert_eq!(
+            err.to_string(),
+            "/nonexistent/missing: No such file or directory"
+        );
+        let mut context = ExecContext::new();
+        assert_eq!(
+            exec_command(&mut context, "cat < /nonexistent/missing || x=1"),
+            Ok(0)
+        );
+        assert_eq!(context.get_variable("x"), Some("1"));
+    }
+
+    #[test]
+    fn test_parse_command_precedence() {
+        let a = || single(Invocation::new("a"));
+        let e = || single(Invocation::new("e"));
+        assert_eq!(
+            parse_command("a | b && c || d; e"),
+            Ok(CommandTree::Sequence(
+                Box::new(CommandTree::Or(
+                    Box::new(CommandTree::And(
+                        Box::new(pipeline(vec![Invocation::new("a"), Invocation::new("b")])),
+                        Box::new(single(Invocation::new("c")))
+                    )),
+                    Box::new(single(Invocation::new("d")))
+                )),
+                Box::new(e())
+            ))
+        );
+        assert_eq!(
+            parse_command("a || e && a;"),
+            Ok(CommandTree::And(
+                Box::new(CommandTree::Or(Box::new(a()), Box::new(e()))),
+                Box::new(a())
+            ))
+        );
+        assert_eq!(parse_command("a &&"), Err(SyntaxError::ExpectedString));
+    }
+
+    #[test]
+    fn test_exec_command_short_circuit() {
+        let mut context = ExecContext::new();
+        assert_eq!(exec_command(&mut context, "a=1 && b=1 || c=1; d=1"), Ok(0));
+        assert_eq!(context.get_variable("a"), Some("1"));
+        assert_eq!(context.get_variable("b"), Some("1"));
+        assert_eq!(context.get_variable("c"), None);
+        assert_eq!(context.get_variable("d"), Some("1"));
+        assert_eq!(exec_command(&mut context, "false && e=1; f=1"), Ok(0));
+        assert_eq!(context.get_variable("e"), None);
+        assert_eq!(context.get_variable("f"), Some("1"));
+        assert_eq!(exec_command(&mut context, "g=1 || h=1; i=1"), Ok(0));
+        assert_eq!(context.get_variable("h"), None);
+        assert_eq!(context.get_variable("i"), Some("1"));
+        assert_eq!(exec_command(&mut context, "false || true && j=1"), Ok(0));
+        assert_eq!(context.get_variable("j"), Some("1"));
+        assert_eq!(exec_command(&mut context, "true; false"), Ok(1));
     }
 }
@@ -1,49 +1,664 @@
-use lazy_static::lazy_static;
-use regex::{Captures, Regex};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use crate::{command::SyntaxError, parse::Statement};
+use crate::{
+    parse::{Arm, ArmPattern, Assignment, Function, Statement, Value},
+    pattern::Pattern,
+    process::Process,
+};
 
-lazy_static! {
-    static ref RE_SIMPLE_VARIABLE: Regex = Regex::new(r"\$([a-zA-Z0-9]+)").unwrap();
-    static ref RE_BRACED_VARIABLE: Regex = Regex::new(r"\$\{[ \t]*([a-zA-Z0-9]+)[ \t]*\}").unwrap();
-    static ref RE_SPACE_SEPERATOR: Regex = Regex::new(r"[ \t]+").unwrap();
+/// Whether execution should carry on with the next statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Normal,
+    Exit,
+    /// `break n`, which stops the `n` innermost loops.
+    Break(u32),
+    /// `continue n`, which stops the `n - 1` innermost loops and moves on to the next iteration of
+    /// the one around them.
+    Continue(u32),
+    /// `return`, which stops the innermost function.
+    Return,
+    /// A command failed inside a `try` block, which stops everything up to its `except` block.
+    Failure,
 }
+
+/// Settings changed with the `set` builtin.
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Whether glob patterns match files starting with `.` without an explicit leading `.`.
+    pub dotglob: bool,
+    /// Whether the shell exits as soon as a command fails, other than in a condition or on the left
+    /// of `&&`.
+    pub errexit: bool,
+    /// Whether a glob pattern that matches nothing is an error.
+    pub failglob: bool,
+    /// Whether expanding a variable that isn't set is an error.
+    pub nounset: bool,
+    /// Whether a glob pattern that matches nothing expands to nothing, rather than itself.
+    pub nullglob: bool,
+    /// Whether a pipeline fails if any of its invocations fail, not just the last one.
+    pub pipefail: bool,
+    /// Whether each command is printed to stderr after it is expanded, prefixed by `$PS4`.
+    pub xtrace: bool,
+}
+
+/// The options that have a single letter, like `set -e`.
+const SHORT_OPTIONS: [(char, &str); 3] = [('e', "errexit"), ('u', "nounset"), ('x', "xtrace")];
+
+impl Options {
+    /// Every option along with its name, in the order `set -o` lists them.
+    pub fn flags(&mut self) -> [(&'static str, &mut bool); 7] {
+        [
+            ("dotglob", &mut self.dotglob),
+            ("errexit", &mut self.errexit),
+            ("failglob", &mut self.failglob),
+            ("nounset", &mut self.nounset),
+            ("nullglob", &mut self.nullglob),
+            ("pipefail", &mut self.pipefail),
+            ("xtrace", &mut self.xtrace),
+        ]
+    }
+    pub fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        self.flags()
+            .into_iter()
+            .find(|(flag, _)| *flag == name)
+            .map(|(_, enabled)| enabled)
+    }
+    /// Applies options like `-eu`, `+x` and `-o pipefail` from the start of `args`, as given to
+    /// `set` or the shell itself. Returns how many of the arguments were options.
+    pub fn apply(&mut self, args: &[String]) -> Result<usize, String> {
+        let mut index = 0;
+        while let Some(arg) = args.get(index) {
+            let enable = match arg.chars().next() {
+                Some('-') => true,
+                Some('+') => false,
+                _ => break,
+            };
+            let letters = &arg[1..];
+            let is_option = |c| c == 'o' || SHORT_OPTIONS.iter().any(|(letter, _)| *letter == c);
+            if letters.is_empty() || !letters.chars().all(is_option) {
+                break;
+            }
+            index += 1;
+            for letter in letters.chars() {
+                let name = match SHORT_OPTIONS.iter().find(|(short, _)| *short == letter) {
+                    Some((_, name)) => *name,
+                    None => {
+                        // `-o name` takes the name from the next argument
+                        let name = args.get(index);
+                        index += 1;
+                        name.ok_or_else(|| format!("{}: option requires an argument", arg))?
+                            .as_str()
+                    }
+                };
+                let flag = self.flag_mut(name);
+                *flag.ok_or_else(|| format!("{}: invalid option name", name))? = enable;
+            }
+        }
+        Ok(index)
+    }
+}
+
+/// What a function call replaces, to be put back when it returns.
+#[derive(Debug)]
+struct Frame {
+    arguments: Vec<String>,
+    loops: u32,
+    /// The values of variables made local to the function from before it was called, or `None` if
+    /// they weren't set.
+    shadowed: HashMap<String, Option<Variable>>,
+}
+
+/// The value of a shell variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Variable {
+    String(String),
+    List(Vec<String>),
+    /// A map from keys to values, which are kept sorted by key.
+    Map(BTreeMap<String, String>),
+}
+
 #[derive(Debug)]
 pub struct ExecContext {
-    strings: HashMap<String, String>,
+    variables: HashMap<String, Variable>,
+    exported: HashSet<String>,
+    /// The positional parameters `$1`, `$2`, ...
+    arguments: Vec<String>,
+    status: i32,
+    flow: Flow,
+    /// How many loops are currently running in the innermost function, or outside of any.
+    loops: u32,
+    functions: HashMap<String, Function>,
+    /// How many `try` blocks are running, outside of any condition.
+    trying: u32,
+    /// Whether a condition is running, where failures don't stop the shell.
+    in_condition: bool,
+    /// The command that made a `try` block fail and its status.
+    failure: Option<(String, i32)>,
+    /// Whether the status of the last and-or list doesn't count as a failure, because it came from
+    /// the left of a `&&`.
+    failure_exempt: bool,
+    /// The words of the last pipeline that started, which become `$ERROR_COMMAND` if it fails.
+    last_command: String,
+    /// Whether commands are being read from a terminal, rather than a script.
+    interactive: bool,
+    /// The functions currently running, innermost last.
+    frames: Vec<Frame>,
+    pipestatus: Vec<i32>,
+    jobs: Vec<Process>,
+    last_job: Option<u32>,
+    /// The status of the last command substitution, which becomes the status of a command that
+    /// only assigns variables.
+    substitution_status: Option<i32>,
+    /// Why the word being expanded couldn't be, if it couldn't.
+    expansion_error: Option<String>,
+    options: Options,
 }
 
 impl ExecContext {
     pub fn new() -> ExecContext {
         ExecContext {
-            strings: HashMap::new(),
+            variables: HashMap::new(),
+            exported: HashSet::new(),
+            arguments: vec![],
+            status: 0,
+            flow: Flow::Normal,
+            loops: 0,
+            functions: HashMap::new(),
+            trying: 0,
+            in_condition: false,
+            failure: None,
+            failure_exempt: false,
+            last_command: String::new(),
+            interactive: false,
+            frames: vec![],
+            pipestatus: vec![],
+            jobs: vec![],
+            last_job: None,
+            substitution_status: None,
+            expansion_error: None,
+            options: Options::default(),
+        }
+    }
+    /// The exit status of the most recently executed command.
+    pub fn status(&self) -> i32 {
+        self.status
+    }
+    pub fn set_status(&mut self, status: i32) {
+        self.status = status;
+    }
+    pub fn set_substitution_status(&mut self, status: i32) {
+        self.status = status;
+        self.substitution_status = Some(status);
+    }
+    pub fn take_substitution_status(&mut self) -> Option<i32> {
+        self.substitution_status.take()
+    }
+    /// Records that an expansion failed. Only the first failure is kept, since later ones are
+    /// often caused by it.
+    pub fn fail_expansion(&mut self, message: String) {
+        self.expansion_error.get_or_insert(message);
+    }
+    pub fn take_expansion_error(&mut self) -> Option<String> {
+        self.expansion_error.take()
+    }
+    /// Records the statuses of every invocation in a pipeline as `$PIPESTATUS`, returning the
+    /// status of the pipeline as a whole.
+    pub fn set_pipeline_status(&mut self, statuses: Vec<i32>) -> i32 {
+        let last = statuses.last().copied().unwrap_or(0);
+        self.status = if self.options.pipefail {
+            statuses
+                .iter()
+                .rev()
+                .copied()
+                .find(|status| *status != 0)
+                .unwrap_or(0)
+        } else {
+            last
+        };
+        self.pipestatus = statuses;
+        self.failure_exempt = false;
+        self.status
+    }
+    /// Marks the status of the current and-or list as not being a failure, because it came from
+    /// the left of a `&&`.
+    pub fn exempt_failure(&mut self) {
+        self.failure_exempt = true;
+    }
+    pub fn set_last_command(&mut self, command: String) {
+        self.last_command = command;
+    }
+    /// Checks the status of an and-or list that just ran, which fails the innermost `try` block or
+    /// exits for `errexit` if it isn't 0.
+    pub fn check_failure(&mut self, status: i32) {
+        if status != 0 && !self.failure_exempt && !self.is_interrupted() {
+            self.fail(self.last_command.clone(), status);
+        }
+    }
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+    /// Keeps track of a process running in the background, so that it can be reaped later.
+    pub fn add_job(&mut self, job: Process) {
+        self.last_job = job.id();
+        self.jobs.push(job);
+    }
+    /// Cleans up background processes that have finished.
+    pub fn reap_jobs(&mut self) {
+        self.jobs.retain_mut(|job| job.try_wait().is_none());
+    }
+    /// Stops executing statements, e.g. because of the `exit` builtin.
+    pub fn exit(&mut self, status: i32) {
+        self.status = status;
+        self.flow = Flow::Exit;
+    }
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+    pub fn is_exiting(&self) -> bool {
+        self.flow == Flow::Exit
+    }
+    /// Whether the rest of the current block should be skipped, because of `exit`, `break` or
+    /// `continue`.
+    pub fn is_interrupted(&self) -> bool {
+        self.flow != Flow::Normal
+    }
+    /// Stops the `count` innermost loops, or all of them if there aren't that many. Returns false
+    /// if there is no loop to stop.
+    pub fn break_loops(&mut self, count: u32) -> bool {
+        if self.loops == 0 {
+            return false;
         }
+        self.flow = Flow::Break(count.min(self.loops));
+        true
+    }
+    /// Skips to the next iteration of the `count`th innermost loop, or the outermost one if there
+    /// aren't that many. Returns false if there is no loop to continue.
+    pub fn continue_loops(&mut self, count: u32) -> bool {
+        if self.loops == 0 {
+            return false;
+        }
+        self.flow = Flow::Continue(count.min(self.loops));
+        true
+    }
+    /// Stops the innermost function with the given status. Returns false if no function is
+    /// running.
+    pub fn return_from_function(&mut self, status: i32) -> bool {
+        if self.frames.is_empty() {
+            return false;
+        }
+        self.status = status;
+        self.flow = Flow::Return;
+        true
+    }
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.get(name)
+    }
+    /// Runs a function with the given arguments as its positional parameters, returning its
+    /// status.
+    pub fn call_function(&mut self, function: Function, args: &[String]) -> i32 {
+        self.frames.push(Frame {
+            arguments: std::mem::replace(&mut self.arguments, args.to_vec()),
+            loops: std::mem::take(&mut self.loops),
+            shadowed: HashMap::new(),
+        });
+        for (index, parameter) in function.parameters.iter().enumerate() {
+            self.make_local(parameter);
+            if let Some(arg) = args.get(index) {
+                self.set_variable(parameter, arg);
+            }
+        }
+        self.status = 0;
+        self.execute(function.body);
+        if self.flow == Flow::Return {
+            self.flow = Flow::Normal;
+        }
+        let frame = self.frames.pop().unwrap();
+        self.arguments = frame.arguments;
+        self.loops = frame.loops;
+        self.restore_variables(frame.shadowed);
+        self.status
+    }
+    /// Runs a function or builtin with the assignments before it, like `FOO=1 f`, bound as
+    /// exported variables, putting back the old values and export status afterwards.
+    pub fn with_assignments<T>(
+        &mut self,
+        assignments: &[(String, String)],
+        run: impl FnOnce(&mut Self) -> T,
+    ) -> T {
+        let mut shadowed = HashMap::new();
+        let mut unexported = vec![];
+        for (name, value) in assignments {
+            shadowed
+                .entry(name.clone())
+                .or_insert_with(|| self.variables.get(name).cloned());
+            if self.exported.insert(name.clone()) {
+                unexported.push(name.clone());
+            }
+            self.set_variable(name, value);
+        }
+        let result = run(self);
+        self.restore_variables(shadowed);
+        for name in unexported {
+            self.exported.remove(&name);
+        }
+        result
+    }
+    /// Puts back the variables that were shadowed by a function call or temporary assignments.
+    fn restore_variables(&mut self, shadowed: HashMap<String, Option<Variable>>) {
+        for (name, value) in shadowed {
+            match value {
+                Some(value) => self.variables.insert(name, value),
+                None => self.variables.remove(&name),
+            };
+        }
+    }
+    /// Makes a variable local to the innermost function, unsetting it until the function assigns
+    /// it and restoring its old value when the function returns. Returns false if no function is
+    /// running.
+    pub fn make_local(&mut self, name: &str) -> bool {
+        let Some(frame) = self.frames.last_mut() else {
+            return false;
+        };
+        if !frame.shadowed.contains_key(name) {
+            frame
+                .shadowed
+                .insert(name.to_string(), self.variables.get(name).cloned());
+        }
+        self.variables.remove(name);
+        true
+    }
+    /// Imports the shell's own environment as exported variables.
+    pub fn import_environment(&mut self) {
+        for (name, value) in std::env::vars() {
+            self.set_variable(&name, &value);
+            self.export_variable(&name);
+        }
+    }
+    /// Gets the value of a string variable.
+    pub fn get_variable(&self, name: &str) -> Option<&str> {
+        match self.variables.get(name)? {
+            Variable::String(value) => Some(value),
+            _ => None,
+        }
+    }
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.variables.get(name)
+    }
+    /// The elements of a list, or the values of a map, including the positional parameters as
+    /// `@` and the statuses of the last pipeline as `PIPESTATUS`. Strings aren't lists.
+    pub fn list_values(&self, name: &str) -> Option<Vec<String>> {
+        match name {
+            "@" => return Some(self.arguments.clone()),
+            "PIPESTATUS" => return Some(self.pipestatus.iter().map(i32::to_string).collect()),
+            _ => {}
+        }
+        match self.variables.get(name)? {
+            Variable::String(_) => None,
+            Variable::List(list) => Some(list.clone()),
+            Variable::Map(map) => Some(map.values().cloned().collect()),
+        }
+    }
+    /// The indices of a list or the keys of a map.
+    pub fn list_keys(&self, name: &str) -> Option<Vec<String>> {
+        let length = match self.variables.get(name) {
+            Some(Variable::Map(map)) => return Some(map.keys().cloned().collect()),
+            _ => self.list_values(name)?.len(),
+        };
+        Some((0..length).map(|index| index.to_string()).collect())
+    }
+    /// Looks up a variable for substitution, including the positional parameters and the special
+    /// parameters `$?`, `$$`, `$!`, `$#`, `$@` and `$*`.
+    pub fn lookup_variable(&self, name: &str) -> Option<String> {
+        if let Ok(index @ 1..) = name.parse::<usize>() {
+            return self.arguments.get(index - 1).cloned();
+        }
+        match name {
+            "#" => Some(self.arguments.len().to_string()),
+            "*" => Some(self.arguments.join(" ")),
+            "?" => Some(self.status.to_string()),
+            "$" => Some(std::process::id().to_string()),
+            "!" => self.last_job.map(|pid| pid.to_string()),
+            // Lists and maps on their own expand to all of their values
+            _ => match self.list_values(name) {
+                Some(values) => Some(values.join(" ")),
+                None => self.get_variable(name).map(str::to_string),
+            },
+        }
+    }
+    pub fn set_variable(&mut self, name: &str, value: &str) {
+        let value = Variable::String(value.to_string());
+        self.variables.insert(name.to_string(), value);
+    }
+    /// Marks a variable to be passed in the environment of child processes.
+    pub fn export_variable(&mut self, name: &str) {
+        self.exported.insert(name.to_string());
+    }
+    /// The exported variables that have a value, sorted by name.
+    pub fn exported_variables(&self) -> Vec<(&str, &str)> {
+        let mut variables: Vec<(&str, &str)> = self
+            .exported
+            .iter()
+            .filter_map(|name| Some((name.as_str(), self.get_variable(name)?)))
+            .collect();
+        variables.sort();
+        variables
+    }
+    /// Binds `$0` to the script being run and `$1`, `$2`, ... to its arguments.
+    pub fn set_arguments(&mut self, script: &str, arguments: &[String]) {
+        self.set_variable("0", script);
+        self.arguments = arguments.to_vec();
+    }
+    /// Drops the first `count` positional parameters, renumbering the rest. Fails if there
+    /// aren't that many.
+    pub fn shift_arguments(&mut self, count: usize) -> bool {
+        if count > self.arguments.len() {
+            return false;
+        }
+        self.arguments.drain(..count);
+        true
     }
     pub fn execute(&mut self, statements: Vec<Statement>) {
         for statement in statements {
+            if self.flow != Flow::Normal {
+                break;
+            }
+            self.reap_jobs();
             match statement {
-                Statement::Assignment(variable, expression) => {
-                    self.exec_assignment(variable, expression)
+                Statement::Assignment(assignment) => self.exec_assignment(assignment),
+                Statement::Command(command) => {
+                    self.exec_command(command);
                 }
-                Statement::Command(command) => self.exec_command(command),
                 Statement::If(conditional, if_block, else_block) => {
                     self.exec_if(conditional, if_block, else_block)
                 }
+                Statement::While(condition, body) => self.exec_while(condition, body),
+                Statement::For(variable, items, body) => self.exec_for(variable, items, body),
+                Statement::Match(value, arms) => self.exec_match(value, arms),
+                Statement::Try(body, handler, cleanup) => self.exec_try(body, handler, cleanup),
+                Statement::Function(function) => {
+                    self.functions.insert(function.name.clone(), function);
+                    self.status = 0;
+                }
             }
         }
     }
-    pub fn exec_assignment(&mut self, variable: String, expression: String) {
-        self.strings
-            .insert(variable, self.perform_substitution(&expression));
+    /// Runs an assignment statement. If it fails, e.g. because of a command substitution in it, the
+    /// innermost `try` block fails too or the shell exits for `errexit`.
+    pub fn exec_assignment(&mut self, assignment: Assignment) {
+        let description = assignment.to_string();
+        self.assign(assignment);
+        if self.status != 0 && !self.is_interrupted() {
+            self.fail(description, self.status);
+        }
+    }
+    fn assign(&mut self, assignment: Assignment) {
+        self.substitution_status = None;
+        self.expansion_error = None;
+        let value = match &assignment.value {
+            Value::String(word) => Variable::String(self.perform_substitution(word)),
+            Value::List(words) => {
+                let mut list = vec![];
+                for word in words {
+                    list.extend(self.expand_word(word));
+                }
+                Variable::List(list)
+            }
+            Value::Map(entries) => Variable::Map(
+                entries
+                    .iter()
+                    .map(|(key, value)| {
+                        let key = self.perform_substitution(key);
+                        (key, self.perform_substitution(value))
+                    })
+                    .collect(),
+            ),
+        };
+        let index = assignment
+            .index
+            .as_ref()
+            .map(|index| self.perform_substitution(index));
+        if let Some(message) = self.take_expansion_error() {
+            self.fail_statement_expansion(message);
+            return;
+        }
+        let name = assignment.variable;
+        let result = match index {
+            Some(index) => self.set_element(&name, &index, value),
+            None if assignment.append => self.append_variable(&name, value),
+            None => {
+                self.variables.insert(name.clone(), value);
+                Ok(())
+            }
+        };
+        self.status = match result {
+            Ok(()) => self.take_substitution_status().unwrap_or(0),
+            Err(message) => {
+                eprintln!("dcsh: {}: {}", name, message);
+                1
+            }
+        };
     }
-    fn exec_command(&mut self, command: String) {
-        match crate::command::exec_command(self, &command) {
-            Err(SyntaxError::CommandNotFound(command)) => {
-                eprintln!("dcsh: command not found: {}", command)
+    /// Adds to the end of a variable. Strings are concatenated, while adding to a list or a map
+    /// adds elements to it. A string becomes a list if a list is added to it.
+    fn append_variable(&mut self, name: &str, value: Variable) -> Result<(), String> {
+        let current = self.variables.remove(name);
+        let appended = match (current, value) {
+            (None, value) => value,
+            (Some(Variable::String(current)), Variable::String(value)) => {
+                Variable::String(current + &value)
+            }
+            (Some(Variable::String(current)), Variable::List(list)) => {
+                Variable::List([vec![current], list].concat())
+            }
+            (Some(Variable::List(mut current)), Variable::String(value)) => {
+                current.push(value);
+                Variable::List(current)
             }
-            Err(_) => eprintln!("dcsh: unknown error"),
-            Ok(_) => {}
+            (Some(Variable::List(mut current)), Variable::List(list)) => {
+                current.extend(list);
+                Variable::List(current)
+            }
+            (Some(Variable::Map(mut current)), Variable::Map(map)) => {
+                current.extend(map);
+                Variable::Map(current)
+            }
+            (Some(current), _) => {
+                self.variables.insert(name.to_string(), current);
+                return Err("can't add that kind of value".to_string());
+            }
+        };
+        self.variables.insert(name.to_string(), appended);
+        Ok(())
+    }
+    /// Sets one element of a list or map. An unset variable becomes a list if the index is 0, or
+    /// a map otherwise. A list index can be negative to count from the end, or one past the end
+    /// to append.
+    fn set_element(&mut self, name: &str, index: &str, value: Variable) -> Result<(), String> {
+        let Variable::String(value) = value else {
+            return Err("an element can only be set to a string".to_string());
+        };
+        let variable = self
+            .variables
+            .entry(name.to_string())
+            .or_insert_with(|| match index {
+                "0" => Variable::List(vec![]),
+                _ => Variable::Map(BTreeMap::new()),
+            });
+        match variable {
+            Variable::String(_) => Err("not a list or map".to_string()),
+            Variable::Map(map) => {
+                map.insert(index.to_string(), value);
+                Ok(())
+            }
+            Variable::List(list) => {
+                let position = index
+                    .parse::<i64>()
+                    .map_err(|_| format!("{}: invalid index", index))?;
+                let length = list.len() as i64;
+                let position = if position < 0 {
+                    position + length
+                } else {
+                    position
+                };
+                match position {
+                    position if position == length => list.push(value),
+                    position if (0..length).contains(&position) => list[position as usize] = value,
+                    _ => return Err(format!("{}: index out of range", index)),
+                }
+                Ok(())
+            }
+        }
+    }
+    pub fn exec_command(&mut self, command: String) -> i32 {
+        if crate::command::exec_command(self, &command).is_err() {
+            eprintln!("dcsh: invalid syntax: {}", command);
+            self.status = 2;
+            self.failure_exempt = false;
+            self.last_command = command;
+            self.check_failure(2);
+        }
+        self.status
+    }
+    /// Reports that a word in a statement like `for` couldn't be expanded.
+    fn fail_statement_expansion(&mut self, message: String) {
+        eprintln!("dcsh: {}", message);
+        self.status = 1;
+        self.abort_on_expansion_error();
+    }
+    /// Exits a non-interactive shell after an expansion fails with `nounset` on, since the script
+    /// can't be trusted to carry on.
+    pub fn abort_on_expansion_error(&mut self) {
+        if self.options.nounset && !self.interactive {
+            self.exit(1);
+        }
+    }
+    /// Runs the condition of an `if` or `while`, which can fail without failing a `try` block or
+    /// exiting because of `errexit`.
+    fn exec_condition(&mut self, condition: String) -> i32 {
+        self.run_as_condition(|context| context.exec_command(condition))
+    }
+    /// Runs something as a condition, such as the left of a `&&` or `||`, so that failures inside
+    /// it, even in functions it calls, don't fail a `try` block or exit because of `errexit`.
+    pub fn run_as_condition<T>(&mut self, run: impl FnOnce(&mut Self) -> T) -> T {
+        let trying = std::mem::take(&mut self.trying);
+        let in_condition = std::mem::replace(&mut self.in_condition, true);
+        let result = run(self);
+        self.trying = trying;
+        self.in_condition = in_condition;
+        result
+    }
+    /// Handles a command in a block failing, which stops the innermost `try` block, or else exits
+    /// if `errexit` is set.
+    fn fail(&mut self, command: String, status: i32) {
+        if self.trying > 0 {
+            self.failure = Some((command, status));
+            self.flow = Flow::Failure;
+        } else if self.options.errexit && !self.in_condition {
+            self.exit(status);
         }
     }
     fn exec_if(
@@ -52,16 +667,484 @@ impl ExecContext {
         if_block: Vec<Statement>,
         else_block: Vec<Statement>,
     ) {
-        println!("IFFY {} {:?} {:?}", conditional, if_block, else_block);
+        if self.exec_condition(conditional) == 0 {
+            self.execute(if_block);
+        } else if else_block.is_empty() {
+            self.status = 0;
+        } else {
+            self.execute(else_block);
+        }
     }
-    pub fn perform_substitution(&self, source: &str) -> String {
-        let empty = "".to_string();
-        let simple_vars = RE_SIMPLE_VARIABLE.replace_all(source, |caps: &Captures| {
-            self.strings.get(&caps[1]).unwrap_or(&empty)
-        });
-        let braced_vars = RE_BRACED_VARIABLE.replace_all(&simple_vars, |caps: &Captures| {
-            self.strings.get(&caps[1]).unwrap_or(&empty)
-        });
-        braced_vars.to_string()
+    /// Runs `body` for as long as `condition` succeeds. The status is that of the last command in
+    /// the body, or 0 if it never ran.
+    fn exec_while(&mut self, condition: String, body: Vec<Statement>) {
+        let mut status = 0;
+        self.loops += 1;
+        loop {
+            if self.exec_condition(condition.clone()) == 0 && !self.is_interrupted() {
+                self.execute(body.clone());
+                status = self.status;
+            } else if !self.is_interrupted() {
+                break;
+            }
+            if !self.finish_iteration() {
+                break;
+            }
+        }
+        self.loops -= 1;
+        if !self.is_exiting() {
+            self.status = status;
+        }
+    }
+    /// Runs `body` once for each field the items expand to, with `variable` set to it. An item that
+    /// is just a command substitution gives a field for each line of its output. The status is that
+    /// of the last command in the body, or 0 if it never ran.
+    fn exec_for(&mut self, variable: String, items: String, body: Vec<Statement>) {
+        self.expansion_error = None;
+        let Some(values) = self.expand_words(&items) else {
+            eprintln!("dcsh: invalid syntax: {}", items);
+            self.status = 2;
+            return;
+        };
+        if let Some(message) = self.take_expansion_error() {
+            self.fail_statement_expansion(message);
+            return;
+        }
+        let mut status = 0;
+        self.loops += 1;
+        for value in values {
+            self.set_variable(&variable, &value);
+            self.execute(body.clone());
+            status = self.status;
+            if !self.finish_iteration() {
+                break;
+            }
+        }
+        self.loops -= 1;
+        if !self.is_exiting() {
+            self.status = status;
+        }
+    }
+    /// Runs the first arm with a pattern that matches the value. The status is 0 if none do.
+    fn exec_match(&mut self, value: String, arms: Vec<Arm>) {
+        self.expansion_error = None;
+        let value = self.perform_substitution(&value);
+        for arm in arms {
+            for pattern in &arm.patterns {
+                let matched = match pattern {
+                    ArmPattern::Glob(pattern) => {
+                        Pattern::from_segments(&self.expand_segments(pattern)).matches(&value)
+                    }
+                    ArmPattern::Regex(regex) => match regex::Regex::new(regex) {
+                        Ok(regex) => regex.is_match(&value),
+                        Err(_) => {
+                            eprintln!("dcsh: match: invalid regex: {}", regex);
+                            self.status = 2;
+                            return;
+                        }
+                    },
+                    ArmPattern::Default => true,
+                };
+                if let Some(message) = self.take_expansion_error() {
+                    self.fail_statement_expansion(message);
+                    return;
+                }
+                if matched {
+                    self.status = 0;
+                    self.execute(arm.body);
+                    return;
+                }
+            }
+        }
+        self.status = 0;
+    }
+    /// Runs a `try` block. If a command in it fails, the `except` block runs with `$ERROR_COMMAND`
+    /// and `$ERROR_STATUS` describing the failure, or else the failure carries on to any enclosing
+    /// `try` block. The `finally` block always runs afterwards, even if the shell is exiting.
+    fn exec_try(&mut self, body: Vec<Statement>, handler: Vec<Statement>, cleanup: Vec<Statement>) {
+        self.trying += 1;
+        self.execute(body);
+        self.trying -= 1;
+        if self.flow == Flow::Failure {
+            let (command, status) = self.failure.take().unwrap();
+            self.flow = Flow::Normal;
+            self.status = status;
+            if handler.is_empty() {
+                self.failure = Some((command, status));
+            } else {
+                self.set_variable("ERROR_COMMAND", &command);
+                self.set_variable("ERROR_STATUS", &status.to_string());
+                self.execute(handler);
+            }
+        }
+        let flow = std::mem::replace(&mut self.flow, Flow::Normal);
+        let status = self.status;
+        self.execute(cleanup);
+        if self.flow == Flow::Normal {
+            self.flow = flow;
+            self.status = status;
+        }
+        if self.flow == Flow::Normal && self.failure.is_some() {
+            // Without an except block, the failure is handled by an enclosing try block
+            let (command, status) = self.failure.take().unwrap();
+            self.fail(command, status);
+        }
+    }
+    /// Handles any `break` or `continue` at the end of an iteration of the innermost loop,
+    /// returning whether the loop should carry on.
+    fn finish_iteration(&mut self) -> bool {
+        match self.flow {
+            Flow::Normal => true,
+            Flow::Exit | Flow::Return | Flow::Failure => false,
+            Flow::Break(1) => {
+                self.flow = Flow::Normal;
+                false
+            }
+            Flow::Continue(1) => {
+                self.flow = Flow::Normal;
+                true
+            }
+            Flow::Break(count) => {
+                self.flow = Flow::Break(count - 1);
+                false
+            }
+            Flow::Continue(count) => {
+                self.flow = Flow::Continue(count - 1);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_special_parameters() {
+        let mut context = ExecContext::new();
+        context.set_status(3);
+        assert_eq!(context.perform_substitution("$? ${?}"), "3 3");
+        assert_eq!(
+            context.perform_substitution("$$"),
+            std::process::id().to_string()
+        );
+        assert_eq!(context.perform_substitution("[$!]"), "[]");
+        let arguments = ["a b".to_string(), "c".to_string()];
+        context.set_arguments("script", &arguments);
+        assert_eq!(
+            context.perform_substitution("$0 $# $2 [$3] ${1}"),
+            "script 2 c [] a b"
+        );
+        assert!(context.shift_arguments(1));
+        assert!(!context.shift_arguments(2));
+        assert_eq!(context.perform_substitution("$# $1"), "1 c");
+    }
+
+    #[test]
+    fn test_pipeline_status() {
+        let mut context = ExecContext::new();
+        assert_eq!(context.set_pipeline_status(vec![1, 2, 0]), 0);
+        assert_eq!(context.perform_substitution("$PIPESTATUS"), "1 2 0");
+        context.options_mut().pipefail = true;
+        assert_eq!(context.set_pipeline_status(vec![1, 2, 0]), 2);
+        assert_eq!(context.set_pipeline_status(vec![0, 0]), 0);
+        assert_eq!(context.status(), 0);
+    }
+
+    #[test]
+    fn test_while_loop() {
+        let mut context = ExecContext::new();
+        let source = "
+i = 0
+out =
+while test $i -lt 9:
+    i = $((i + 1))
+    if test $i = 2:
+        continue
+    elif test $i = 6:
+        break
+    j = 0
+    while true:
+        j = $((j + 1))
+        if test $j = 2:
+            continue 2
+        out = $out$i
+out = $out-$j
+while false:
+    out = never
+";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(context.perform_substitution("$out $i"), "1345-2 6");
+        assert_eq!(context.status(), 0);
+        context.execute(crate::parse::parse("break\nout = after").unwrap());
+        assert_eq!(context.perform_substitution("$out"), "after");
+        let source = "while true:\n  while true:\n    break 5; out = no\n  out = no\nout = yes";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(context.perform_substitution("$out"), "yes");
+        context.execute(crate::parse::parse("while true:\n  exit 3\nout = no").unwrap());
+        assert!(context.is_exiting());
+        assert_eq!(context.status(), 3);
+        assert_eq!(context.perform_substitution("$out"), "yes");
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let mut context = ExecContext::new();
+        let source = "
+files = [x, \"y z\"]
+out =
+for item in a\\ b {1..3} \"$files\" $(printf 'p\\nq r'):
+    if test \"$item\" = 2:
+        continue
+    out = \"$out<$item>\"
+    for inner in 1 2:
+        if test \"$item\" = x:
+            break 2
+";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(
+            context.perform_substitution("$out $item"),
+            "<a b><1><3><x> x"
+        );
+        // A command substitution on its own gives an item per line, while in a longer word its
+        // output is split into fields as usual
+        let source = "out =\nfor item in $(printf 'p\\n\\nq r\\n') `echo a b` x$(echo c d) $((1 + 1)) \"${files[1]}\":\n  out = \"$out<$item>\"";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(
+            context.perform_substitution("$out"),
+            "<p><q r><a b><xc><d><2><y z>"
+        );
+        context.execute(crate::parse::parse("for item in:\n  out = never").unwrap());
+        assert_eq!(context.status(), 0);
+        context.execute(crate::parse::parse("for item in ${x:?}:\n  out = never").unwrap());
+        assert_eq!(context.status(), 1);
+        assert_eq!(context.perform_substitution("$item"), "y z");
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut context = ExecContext::new();
+        let source = "
+x = global
+y = global
+def f(a, b):
+    local x=local y
+    out = \"$out[$a|$b|$#|$*|$x]\"
+    if test $a = 2:
+        return 3
+    for i in 1 2:
+        while true:
+            g $i
+            break
+    out = $out.
+def g():
+    out = \"$out($1 $x)\"
+    x = g
+f 1 2 3
+f 2
+out = \"$out $? $x $y $a\"
+";
+        context.set_arguments("script", &["arg".to_string()]);
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(
+            context.perform_substitution("$out $1"),
+            "[1|2|3|1 2 3|local](1 local)(2 g).[2||1|2|local] 3 global global  arg"
+        );
+        // Assignments before a function call are exported only while it runs
+        let source = "
+def e():
+    out = \"$x $z $(sh -c 'echo $x $z')\"
+x=temp z=new e
+out = \"$out|$x|${z-unset}\"
+";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(
+            context.perform_substitution("$out"),
+            "temp new temp new|global|unset"
+        );
+        assert!(context.exported_variables().is_empty());
+        context.execute(crate::parse::parse("return 4\nout = after").unwrap());
+        assert_eq!(context.status(), 0);
+        context
+            .execute(crate::parse::parse("def h():\n  exit 5\n  out = no\nh\nout = no").unwrap());
+        assert!(context.is_exiting());
+        assert_eq!(context.status(), 5);
+        assert_eq!(context.perform_substitution("$out"), "after");
+    }
+
+    #[test]
+    fn test_match() {
+        let mut context = ExecContext::new();
+        let source = "
+ext = gz
+def kind(file):
+    match $file:
+        *.tar.$ext | *.tgz:
+            out = \"$out archive\"
+        \"*\":
+            out = \"$out star\"
+        /^v[0-9]+(\\.[0-9]+)*$/:
+            out = \"$out version\"
+        _:
+            out = \"$out other\"
+            return 1
+for file in a.tar.gz b.tgz a.tar.xz \\* v1.2 v1.:
+    kind \"$file\"
+";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(
+            context.perform_substitution("$out"),
+            " archive archive other star version other"
+        );
+        assert_eq!(context.status(), 1);
+        context.execute(crate::parse::parse("match x:\n  y:\n    out = no").unwrap());
+        assert_eq!(context.status(), 0);
+        context.execute(crate::parse::parse("match x:\n  /(/:\n    out = no").unwrap());
+        assert_eq!(context.status(), 2);
+    }
+
+    #[test]
+    fn test_try() {
+        let mut context = ExecContext::new();
+        let source = "
+def check(value):
+    test $value = ok
+try:
+    out = start
+    false && out=never; out=$out.and
+    false || out=$out.or
+    check bad || out=$out.fallback
+    if false:
+        out = never
+    for value in ok bad:
+        check $value
+        out = $out.$value
+finally:
+    out = $out.finally
+";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(
+            context.perform_substitution("$out $?"),
+            "start.and.or.fallback.ok.finally 1"
+        );
+        assert!(!context.is_interrupted());
+        // A failure before a `;` stops the rest of the line too
+        let source = "
+try:
+    out=first; false; out=continued
+except:
+    out = \"$out caught $ERROR_STATUS $ERROR_COMMAND\"
+";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(context.perform_substitution("$out"), "first caught 1 false");
+        let source = "
+try:
+    try:
+        sh -c 'exit 3'
+        out = never
+    finally:
+        out = inner
+    out = never
+except:
+    out = \"$out $ERROR_STATUS $? $ERROR_COMMAND\"
+    try:
+        exit 4
+    finally:
+        out = $out.exiting
+";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(
+            context.perform_substitution("$out"),
+            "inner 3 3 sh -c 'exit 3'.exiting"
+        );
+        assert!(context.is_exiting());
+        assert_eq!(context.status(), 4);
+    }
+
+    #[test]
+    fn test_options() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let mut options = Options::default();
+        assert_eq!(
+            options.apply(&args(&["-eu", "-o", "pipefail", "script"])),
+            Ok(3)
+        );
+        assert!(options.errexit && options.nounset && options.pipefail && !options.xtrace);
+        assert_eq!(
+            options.apply(&args(&["+ue", "-xo", "nullglob", "-c"])),
+            Ok(3)
+        );
+        assert!(!options.errexit && !options.nounset && options.xtrace && options.nullglob);
+        assert_eq!(options.apply(&args(&["-", "-e"])), Ok(0));
+        assert!(options.apply(&args(&["-o"])).is_err());
+        assert!(options.apply(&args(&["-o", "missing"])).is_err());
+
+        let mut context = ExecContext::new();
+        context.options_mut().nounset = true;
+        assert_eq!(context.perform_substitution("${x:-a}${x-b}${x+c}$#"), "ab0");
+        assert_eq!(context.take_expansion_error(), None);
+        for word in ["$x", "${x}", "${#x}", "${x#a}", "${x[0]}", "$1"] {
+            context.perform_substitution(word);
+            assert!(context.take_expansion_error().is_some(), "{}", word);
+        }
+        let source = "
+set -e
+def check():
+    false
+    out = never
+false && out=never
+if check:
+    out = never
+while false:
+    out = never
+out = before
+false
+out = after
+";
+        context.options_mut().nounset = false;
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(context.perform_substitution("$out"), "before");
+        assert!(context.is_exiting());
+        assert_eq!(context.status(), 1);
+
+        let run = |source: &str, interactive| {
+            let mut context = ExecContext::new();
+            context.set_interactive(interactive);
+            context.execute(crate::parse::parse(source).unwrap());
+            let out = context.perform_substitution("$out");
+            (out, context.is_exiting(), context.status())
+        };
+        assert_eq!(
+            run("set -e\nout=a; false; out=b", false),
+            ("a".into(), true, 1)
+        );
+        assert_eq!(
+            run("set -e\nfalse || out=a; false && out=no; out=$out.b", false),
+            ("a.b".into(), false, 0)
+        );
+        assert_eq!(
+            run(
+                "set -e\ndef f():\n    false\n    out=$out.f\n    false\nout=a\nf || out=$out.or\nout=$out.b",
+                false
+            ),
+            ("a.f.or.b".into(), false, 0)
+        );
+        assert_eq!(
+            run("set -e\nout = a\nx = $(exit 3)\nout = b", false),
+            ("a".into(), true, 3)
+        );
+        assert_eq!(
+            run("set -u\nout = a\necho $nope\nout = b", false),
+            ("a".into(), true, 1)
+        );
+        assert_eq!(
+            run("set -u\nout = $nope\nout = b", false),
+            ("".into(), true, 1)
+        );
+        assert_eq!(
+            run("set -u\necho $nope\nout = b", true),
+            ("b".into(), false, 0)
+        );
     }
 }
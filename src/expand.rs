@@ -0,0 +1,1252 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    ffi::{CStr, CString},
+    io::{self, Read},
+};
+
+use crate::{
+    arith::ArithError,
+    command::describe_io_error,
+    exec::{ExecContext, Variable},
+    glob::glob,
+    pattern::{self, Pattern},
+    process,
+    redirect::Redirections,
+};
+
+lazy_static! {
+    /// The blanks that the results of unquoted expansions are split into fields at.
+    static ref RE_SPACE_SEPERATOR: Regex = Regex::new(r"[ \t\n]+").unwrap();
+}
+
+/// A piece of a word after expansion, remembering whether it came from a quoted part of the word.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub text: String,
+    pub quoted: bool,
+}
+
+impl Segment {
+    pub fn new(text: &str, quoted: bool) -> Segment {
+        Segment {
+            text: text.to_string(),
+            quoted,
+        }
+    }
+}
+
+/// Characters that a backslash escapes inside double quotes. Before anything else, a backslash is
+/// kept as is.
+const DOUBLE_QUOTE_ESCAPES: &[char] = &['$', '`', '"', '\\', '\n'];
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Splits off the variable name at the start of `source`, e.g. `foo` in `foo.txt` or `1` in `12`.
+fn take_name(source: &str) -> Option<(&str, &str)> {
+    let first = source.chars().next()?;
+    let length = if first.is_ascii_digit() || "?$!#@*".contains(first) {
+        1
+    } else if is_name_start(first) {
+        source.find(|c| !is_name_char(c)).unwrap_or(source.len())
+    } else {
+        return None;
+    };
+    Some(source.split_at(length))
+}
+
+/// Characters that a backslash escapes inside backticks.
+const BACKTICK_ESCAPES: &[char] = &['$', '`', '\\'];
+
+/// Skips the character at the start of `source` along with anything it quotes or encloses,
+/// returning what follows, or `None` if a quote or substitution is never closed. Single quotes
+/// are just another character inside double quotes.
+fn skip_unit(source: &str, double_quoted: bool) -> Option<&str> {
+    let c = source.chars().next()?;
+    let rest = &source[c.len_utf8()..];
+    Some(match c {
+        '\\' => match rest.chars().next() {
+            Some(escaped) => &rest[escaped.len_utf8()..],
+            None => rest,
+        },
+        '\'' if !double_quoted => &rest[rest.find('\'')? + 1..],
+        '"' if !double_quoted => &rest[closing_double_quote(rest)? + 1..],
+        '`' => &rest[closing_backtick(rest)? + 1..],
+        '$' if rest.starts_with('(') => &rest[1 + closing_paren(&rest[1..])? + 1..],
+        '$' if rest.starts_with('{') => &rest[1 + closing_brace(&rest[1..])? + 1..],
+        _ => rest,
+    })
+}
+
+/// Finds the `"` that closes a double quoted string, given what follows the opening quote.
+fn closing_double_quote(source: &str) -> Option<usize> {
+    let mut rest = source;
+    while !rest.starts_with('"') {
+        rest = skip_unit(rest, true)?;
+    }
+    Some(source.len() - rest.len())
+}
+
+/// Finds the backtick that closes a command substitution, given what follows the opening one.
+fn closing_backtick(source: &str) -> Option<usize> {
+    let mut rest = source;
+    while !rest.starts_with('`') {
+        let c = rest.chars().next()?;
+        let length = match c {
+            '\\' => 1 + rest[1..].chars().next().map_or(0, char::len_utf8),
+            _ => c.len_utf8(),
+        };
+        rest = &rest[length..];
+    }
+    Some(source.len() - rest.len())
+}
+
+/// Finds the `)` that closes a command substitution, given what follows the `$(`.
+fn closing_paren(source: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut rest = source;
+    loop {
+        match rest.chars().next()? {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(source.len() - rest.len()),
+            ')' => depth -= 1,
+            _ => {}
+        }
+        rest = skip_unit(rest, false)?;
+    }
+}
+
+/// Finds the `}` that closes a parameter expansion, given what follows the `${`.
+fn closing_brace(source: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut rest = source;
+    loop {
+        match rest.chars().next()? {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(source.len() - rest.len()),
+            '}' => depth -= 1,
+            _ => {}
+        }
+        rest = skip_unit(rest, false)?;
+    }
+}
+
+/// Finds the first `/` in a parameter expansion that isn't quoted or part of a nested expansion.
+fn unquoted_slash(source: &str) -> Option<usize> {
+    let mut rest = source;
+    while !rest.starts_with('/') {
+        rest = skip_unit(rest, false)?;
+    }
+    Some(source.len() - rest.len())
+}
+
+/// Splits the name off the start of a braced parameter expansion. Unlike a bare `$1`, a braced
+/// positional parameter can have several digits.
+fn take_braced_name(source: &str) -> Option<(&str, &str)> {
+    match source.chars().next()? {
+        c if c.is_ascii_digit() => {
+            let length = source
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(source.len());
+            Some(source.split_at(length))
+        }
+        _ => take_name(source),
+    }
+}
+
+/// Splits expanded segments into fields at the blanks in unquoted segments, which can only have
+/// come from expansions since the word itself ends at the first unquoted blank. An unquoted
+/// expansion that is empty disappears, while quotes always make a field, even an empty one.
+fn split_fields(segments: Vec<Segment>) -> Vec<Vec<Segment>> {
+    let mut fields = vec![];
+    let mut field: Option<Vec<Segment>> = None;
+    for segment in segments {
+        if segment.quoted {
+            field.get_or_insert_with(Vec::new).push(segment);
+            continue;
+        }
+        for (index, piece) in RE_SPACE_SEPERATOR.split(&segment.text).enumerate() {
+            if index > 0 {
+                fields.extend(field.take());
+            }
+            if !piece.is_empty() {
+                let piece = Segment::new(piece, false);
+                field.get_or_insert_with(Vec::new).push(piece);
+            }
+        }
+    }
+    fields.extend(field);
+    fields
+}
+
+/// Expands the braces in a raw word, e.g. `a{b,c}` into `ab` and `ac`, or `{1..3}` into `1`, `2`
+/// and `3`. This comes before any other expansion. Braces that are quoted, part of `${...}`, or
+/// hold neither a comma nor a sequence are left as they are.
+pub fn expand_braces(word: &str) -> Vec<String> {
+    let mut rest = word;
+    while let Some(c) = rest.chars().next() {
+        if c == '{' {
+            if let Some((alternatives, end)) = brace_alternatives(&rest[1..]) {
+                let prefix = &word[..word.len() - rest.len()];
+                let suffix = &rest[1 + end + 1..];
+                return alternatives
+                    .iter()
+                    .flat_map(|alternative| {
+                        expand_braces(&format!("{}{}{}", prefix, alternative, suffix))
+                    })
+                    .collect();
+            }
+            rest = &rest[1..];
+        } else {
+            match skip_unit(rest, false) {
+                Some(next) => rest = next,
+                None => break,
+            }
+        }
+    }
+    vec![word.to_string()]
+}
+
+/// Reads the inside of a brace expression, given what follows the `{`. Returns the alternatives it
+/// stands for along with the position of the closing `}`.
+fn brace_alternatives(source: &str) -> Option<(Vec<String>, usize)> {
+    let mut depth = 0;
+    let mut commas = vec![];
+    let mut rest = source;
+    let end = loop {
+        let index = source.len() - rest.len();
+        match rest.chars().next()? {
+            '{' => depth += 1,
+            '}' if depth == 0 => break index,
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(index),
+            _ => {}
+        }
+        rest = skip_unit(rest, false)?;
+    };
+    if commas.is_empty() {
+        return Some((brace_sequence(&source[..end])?, end));
+    }
+    let mut alternatives = vec![];
+    let mut start = 0;
+    for comma in commas.into_iter().chain([end]) {
+        alternatives.push(source[start..comma].to_string());
+        start = comma + 1;
+    }
+    Some((alternatives, end))
+}
+
+/// Expands a sequence like `1..10`, `01..10..2` or `a..e`. Numbers are padded with zeros to the
+/// same width if either end of the sequence is, and letter sequences run between ASCII letters.
+fn brace_sequence(source: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = source.split("..").collect();
+    let (start, end, step) = match parts[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step.parse::<i64>().ok()?.unsigned_abs().max(1)),
+        _ => return None,
+    };
+    let values = |start: i64, end: i64| {
+        let step = step as usize;
+        if start <= end {
+            (start..=end).step_by(step).collect::<Vec<_>>()
+        } else {
+            (end..=start).rev().step_by(step).collect()
+        }
+    };
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        let padded = |number: &str| {
+            let digits = number.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = match padded(start) || padded(end) {
+            true => start.len().max(end.len()),
+            false => 0,
+        };
+        let numbers = values(first, last).into_iter();
+        return Some(numbers.map(|n| format!("{:0width$}", n)).collect());
+    }
+    let letter = |text: &str| match text.as_bytes() {
+        [c] if c.is_ascii_alphabetic() => Some(*c),
+        _ => None,
+    };
+    match (letter(start), letter(end)) {
+        (Some(first), Some(last)) => Some(
+            values(first as i64, last as i64)
+                .into_iter()
+                .map(|c| (c as u8 as char).to_string())
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Looks up the home directory of a user.
+fn home_directory(user: &str) -> Option<String> {
+    let name = CString::new(user).ok()?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return None;
+    }
+    let directory = unsafe { CStr::from_ptr((*entry).pw_dir) };
+    directory.to_str().ok().map(str::to_string)
+}
+
+/// Splits `source` at each unquoted occurrence of `separator`. Returns `None` if a quote is never
+/// closed.
+pub fn split_unquoted(source: &str, separator: char) -> Option<Vec<&str>> {
+    let mut pieces = vec![];
+    let mut start = 0;
+    let mut rest = source;
+    while let Some(c) = rest.chars().next() {
+        if c == separator {
+            let index = source.len() - rest.len();
+            pieces.push(&source[start..index]);
+            start = index + c.len_utf8();
+            rest = &rest[c.len_utf8()..];
+        } else {
+            rest = skip_unit(rest, false)?;
+        }
+    }
+    pieces.push(&source[start..]);
+    Some(pieces)
+}
+
+/// Splits a `[subscript]` off the start of what follows a name in `${...}`.
+fn take_subscript(source: &str) -> Option<(Option<&str>, &str)> {
+    match source.strip_prefix('[') {
+        Some(rest) => {
+            let end = rest.find(']')?;
+            Some((Some(&rest[..end]), &rest[end + 1..]))
+        }
+        None => Some((None, source)),
+    }
+}
+
+/// Finds the length of the word at the start of `source`, which ends at the first unquoted
+/// blank or operator character. Returns `None` if a quote or substitution is never closed.
+pub fn word_length(source: &str) -> Option<usize> {
+    let mut rest = source;
+    while let Some(c) = rest.chars().next() {
+        if " \t\n;&|<>".contains(c) {
+            break;
+        }
+        rest = skip_unit(rest, false)?;
+    }
+    Some(source.len() - rest.len())
+}
+
+/// Removes quotes and backslash escapes from a word without expanding anything in it.
+pub fn unquote(word: &str) -> String {
+    let mut text = String::new();
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            '\'' => text.extend(chars.by_ref().take_while(|c| *c != '\'')),
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some(escaped) if DOUBLE_QUOTE_ESCAPES.contains(&escaped) => {
+                                text.push(escaped)
+                            }
+                            Some(other) => text.extend(['\\', other]),
+                            None => text.push('\\'),
+                        },
+                        _ => text.push(c),
+                    }
+                }
+            }
+            _ => text.push(c),
+        }
+    }
+    text
+}
+
+/// The byte offsets of every character boundary in `text`, including both ends.
+fn boundaries(text: &str) -> impl DoubleEndedIterator<Item = usize> + '_ {
+    text.char_indices()
+        .map(|(index, _)| index)
+        .chain([text.len()])
+}
+
+/// Removes the shortest or longest prefix of `value` that matches a pattern.
+fn remove_prefix<'a>(value: &'a str, pattern: &Pattern, longest: bool) -> &'a str {
+    let mut ends: Vec<usize> = boundaries(value).collect();
+    if longest {
+        ends.reverse();
+    }
+    match ends.into_iter().find(|end| pattern.matches(&value[..*end])) {
+        Some(end) => &value[end..],
+        None => value,
+    }
+}
+
+/// Removes the shortest or longest suffix of `value` that matches a pattern.
+fn remove_suffix<'a>(value: &'a str, pattern: &Pattern, longest: bool) -> &'a str {
+    let mut starts: Vec<usize> = boundaries(value).collect();
+    if !longest {
+        starts.reverse();
+    }
+    match starts
+        .into_iter()
+        .find(|start| pattern.matches(&value[*start..]))
+    {
+        Some(start) => &value[..start],
+        None => value,
+    }
+}
+
+impl ExecContext {
+    /// Expands a single word, removing quotes and substituting variables.
+    pub fn perform_substitution(&mut self, source: &str) -> String {
+        self.expand_segments(source)
+            .into_iter()
+            .map(|segment| segment.text)
+            .collect()
+    }
+
+    /// Expands a word into the fields it stands for. Braces are expanded first, then each
+    /// resulting word is expanded and split into fields, and finally each field is globbed.
+    pub fn expand_word(&mut self, word: &str) -> Vec<String> {
+        let mut fields = vec![];
+        for word in expand_braces(word) {
+            let segments = self.expand_segments(&word);
+            for field in split_fields(segments) {
+                fields.extend(self.expand_pathnames(field));
+            }
+        }
+        fields
+    }
+
+    /// Expands a list of words separated by blanks, such as the items of a `for` loop, into all of
+    /// the fields they stand for. A word that is just a command substitution stands for each line
+    /// of its output instead, so that lines with blanks in them stay whole. Returns `None` if a
+    /// quote is never closed or a word is followed by an operator.
+    pub fn expand_words(&mut self, source: &str) -> Option<Vec<String>> {
+        let mut fields = vec![];
+        let mut rest = source.trim_start_matches([' ', '\t', '\n']);
+        while !rest.is_empty() {
+            let length = word_length(rest)?;
+            if length == 0 {
+                return None;
+            }
+            let word = &rest[..length];
+            match self.substitute_lines(word) {
+                Some(lines) => fields.extend(lines),
+                None => fields.extend(self.expand_word(word)),
+            }
+            rest = rest[length..].trim_start_matches([' ', '\t', '\n']);
+        }
+        Some(fields)
+    }
+
+    /// The non-empty lines output by a word that is just an unquoted `$(...)` or `` `...` ``, or
+    /// `None` for any other word.
+    fn substitute_lines(&mut self, word: &str) -> Option<Vec<String>> {
+        let output = if let Some(inner) = word.strip_prefix("$(") {
+            let command = inner.strip_suffix(')')?;
+            let is_arithmetic = command.starts_with('(') && command.ends_with(')');
+            if closing_paren(inner)? != command.len() || is_arithmetic {
+                return None;
+            }
+            self.substitute_command(command)
+        } else if let Some(inner) = word.strip_prefix('`') {
+            if closing_backtick(inner)? != inner.len() - 1 {
+                return None;
+            }
+            self.expand_backticks(inner).0
+        } else {
+            return None;
+        };
+        let lines = output.lines().filter(|line| !line.is_empty());
+        Some(lines.map(str::to_string).collect())
+    }
+
+    /// Unquoted wildcards make a field a glob pattern that expands to the paths matching it. A
+    /// pattern that matches nothing is kept as is, or removed or made an error by the `nullglob`
+    /// and `failglob` options.
+    fn expand_pathnames(&mut self, segments: Vec<Segment>) -> Vec<String> {
+        let text: String = segments
+            .iter()
+            .map(|segment| segment.text.as_str())
+            .collect();
+        let source = pattern::quote_segments(&segments);
+        if Pattern::new(&source).literal().is_some() {
+            return vec![text];
+        }
+        let paths = glob(&source, self.options().dotglob);
+        if !paths.is_empty() {
+            paths
+        } else if self.options().failglob {
+            self.fail_expansion(format!("no matches found: {}", text));
+            vec![]
+        } else if self.options().nullglob {
+            vec![]
+        } else {
+            vec![text]
+        }
+    }
+
+    /// Expands a `~` at the start of a word, along with the unquoted user name that may follow it
+    /// up to the first `/`. `~+` and `~-` stand for the current and previous directories. Returns
+    /// `None` if there is nothing to expand.
+    fn expand_tilde<'a>(&self, source: &'a str) -> Option<(String, &'a str)> {
+        let prefix = source.strip_prefix('~')?;
+        let end = prefix.find('/').unwrap_or(prefix.len());
+        let (name, rest) = prefix.split_at(end);
+        let directory = match name {
+            "" => self.lookup_variable("HOME")?,
+            "+" => self.lookup_variable("PWD")?,
+            "-" => self.lookup_variable("OLDPWD")?,
+            user if user
+                .chars()
+                .all(|c| c.is_alphanumeric() || "._-".contains(c)) =>
+            {
+                home_directory(user)?
+            }
+            _ => return None,
+        };
+        Some((directory, rest))
+    }
+
+    /// Expands the body of a here-document, which works like a double quoted string except that
+    /// quotes have no special meaning.
+    pub fn perform_heredoc_substitution(&mut self, body: &str) -> String {
+        let mut segments = vec![];
+        self.expand_double_quoted(body, &mut segments, true);
+        segments.into_iter().map(|segment| segment.text).collect()
+    }
+
+    /// Expands a word into segments of quoted and unquoted text. Single quotes suppress all
+    /// expansion, double quotes allow variables and command substitution, and a backslash quotes
+    /// the next character.
+    pub fn expand_segments(&mut self, mut source: &str) -> Vec<Segment> {
+        let mut segments = vec![];
+        if let Some((home, rest)) = self.expand_tilde(source) {
+            segments.push(Segment::new(&home, true));
+            source = rest;
+        }
+        while let Some(c) = source.chars().next() {
+            let rest = &source[c.len_utf8()..];
+            match c {
+                '\\' => match rest.chars().next() {
+                    Some(escaped) => {
+                        segments.push(Segment::new(&escaped.to_string(), true));
+                        source = &rest[escaped.len_utf8()..];
+                    }
+                    None => {
+                        segments.push(Segment::new("\\", false));
+                        source = rest;
+                    }
+                },
+                '\'' => {
+                    let end = rest.find('\'').unwrap_or(rest.len());
+                    segments.push(Segment::new(&rest[..end], true));
+                    source = rest.get(end + 1..).unwrap_or("");
+                }
+                '"' => {
+                    source = self.expand_double_quoted(rest, &mut segments, false);
+                }
+                '$' => {
+                    let (value, remainder) = self.expand_variable(rest);
+                    segments.push(Segment::new(&value, false));
+                    source = remainder;
+                }
+                '`' => {
+                    let (value, remainder) = self.expand_backticks(rest);
+                    segments.push(Segment::new(&value, false));
+                    source = remainder;
+                }
+                _ => {
+                    let end = source
+                        .find(|c| "\\'\"$`".contains(c))
+                        .unwrap_or(source.len());
+                    segments.push(Segment::new(&source[..end], false));
+                    source = &source[end..];
+                }
+            }
+        }
+        segments
+    }
+
+    /// Expands the inside of a double quoted string, returning whatever follows the closing quote.
+    /// A here-document body goes until the end of the source instead.
+    fn expand_double_quoted<'a>(
+        &mut self,
+        mut source: &'a str,
+        segments: &mut Vec<Segment>,
+        heredoc: bool,
+    ) -> &'a str {
+        let mut text = String::new();
+        let mut listed = false;
+        while let Some(c) = source.chars().next() {
+            let rest = &source[c.len_utf8()..];
+            match c {
+                '"' if !heredoc => {
+                    source = rest;
+                    break;
+                }
+                '$' if self.take_list_reference(rest).is_some() => {
+                    let (values, remainder) = self.take_list_reference(rest).unwrap();
+                    // Each value is a separate field, so they are joined by unquoted blanks that
+                    // field splitting will break them apart at
+                    for (index, value) in values.into_iter().enumerate() {
+                        if index > 0 {
+                            segments.push(Segment::new(" ", false));
+                        }
+                        text.push_str(&value);
+                        segments.push(Segment::new(&std::mem::take(&mut text), true));
+                    }
+                    listed = true;
+                    source = remainder;
+                }
+                '\\' => match rest.chars().next() {
+                    Some(escaped)
+                        if DOUBLE_QUOTE_ESCAPES.contains(&escaped)
+                            && !(heredoc && escaped == '"') =>
+                    {
+                        text.push(escaped);
+                        source = &rest[escaped.len_utf8()..];
+                    }
+                    _ => {
+                        text.push('\\');
+                        source = rest;
+                    }
+                },
+                '$' => {
+                    let (value, remainder) = self.expand_variable(rest);
+                    text.push_str(&value);
+                    source = remainder;
+                }
+                '`' => {
+                    let (value, remainder) = self.expand_backticks(rest);
+                    text.push_str(&value);
+                    source = remainder;
+                }
+                _ => {
+                    text.push(c);
+                    source = rest;
+                }
+            }
+        }
+        // Even an empty pair of quotes produces a segment, so that "" is still an argument, unless
+        // it held an empty list like "$@" with no arguments
+        if !(listed && text.is_empty()) {
+            segments.push(Segment { text, quoted: true });
+        }
+        source
+    }
+
+    /// Expands the variable reference or command substitution following a `$`, returning its value
+    /// and the rest of the source. A `$` that doesn't start either is kept as is.
+    fn expand_variable<'a>(&mut self, source: &'a str) -> (String, &'a str) {
+        if let Some(inner) = source.strip_prefix('(') {
+            if let Some(end) = closing_paren(inner) {
+                let value = match inner[..end].strip_prefix('(') {
+                    Some(expression) if expression.ends_with(')') => {
+                        self.expand_arithmetic(&expression[..expression.len() - 1])
+                    }
+                    _ => self.substitute_command(&inner[..end]),
+                };
+                return (value, &inner[end + 1..]);
+            }
+        }
+        if let Some(braced) = source.strip_prefix('{') {
+            if let Some(end) = closing_brace(braced) {
+                let value = match self.expand_parameter(&braced[..end]) {
+                    Ok(value) => value,
+                    Err(message) => {
+                        self.fail_expansion(message);
+                        String::new()
+                    }
+                };
+                return (value, &braced[end + 1..]);
+            }
+        }
+        match take_name(source) {
+            Some((name, remainder)) => {
+                let value = match self.require_set(name, self.lookup_variable(name)) {
+                    Ok(value) => value,
+                    Err(message) => {
+                        self.fail_expansion(message);
+                        String::new()
+                    }
+                };
+                (value, remainder)
+            }
+            None => ("$".to_string(), source),
+        }
+    }
+
+    /// Expands the inside of `${...}`, which is a parameter name optionally followed by an
+    /// operator. Returns a message describing the problem if the expansion fails.
+    fn expand_parameter(&mut self, source: &str) -> Result<String, String> {
+        let bad_substitution = || format!("${{{}}}: bad substitution", source);
+        if let Some(name) = source.strip_prefix('!') {
+            // `${!name[@]}` is the indices of a list or the keys of a map
+            let name = name.strip_suffix("[@]").or(name.strip_suffix("[*]"));
+            let name = name.ok_or_else(bad_substitution)?;
+            return Ok(self.list_keys(name).unwrap_or_default().join(" "));
+        }
+        if let Some(name) = source.strip_prefix('#').filter(|name| !name.is_empty()) {
+            let (name, rest) = take_braced_name(name).ok_or_else(bad_substitution)?;
+            let Some((subscript, "")) = take_subscript(rest) else {
+                return Err(bad_substitution());
+            };
+            // The length of a list or map is how many values it has
+            let length = match (subscript, self.list_values(name)) {
+                (None | Some("@" | "*"), Some(values)) => values.len(),
+                (Some(subscript), _) => {
+                    let value = self.lookup_element(name, subscript)?;
+                    self.require_set(name, value)?.chars().count()
+                }
+                (None, None) => {
+                    let value = self.lookup_variable(name);
+                    self.require_set(name, value)?.chars().count()
+                }
+            };
+            return Ok(length.to_string());
+        }
+        let (name, operation) = take_braced_name(source).ok_or_else(bad_substitution)?;
+        let (subscript, operation) = take_subscript(operation).ok_or_else(bad_substitution)?;
+        let value = match subscript {
+            Some(subscript) => self.lookup_element(name, subscript)?,
+            None => self.lookup_variable(name),
+        };
+        let mut chars = operation.chars();
+        let Some(operator) = chars.next() else {
+            return self.require_set(name, value);
+        };
+        // With a colon, the default and alternative operators treat an empty value as unset
+        let (colon, operator, word) = match (operator, chars.next()) {
+            (':', Some(next @ ('-' | '=' | '?' | '+'))) => (true, next, &operation[2..]),
+            _ => (false, operator, &operation[operator.len_utf8()..]),
+        };
+        let is_set = match &value {
+            Some(value) => !(colon && value.is_empty()),
+            None => false,
+        };
+        match operator {
+            '+' if is_set => Ok(self.perform_substitution(word)),
+            '+' => Ok(String::new()),
+            '-' | '=' | '?' if is_set => Ok(value.unwrap_or_default()),
+            '-' => Ok(self.perform_substitution(word)),
+            '=' => {
+                let word = self.perform_substitution(word);
+                self.set_variable(name, &word);
+                Ok(word)
+            }
+            '?' => {
+                let message = match self.perform_substitution(word) {
+                    message if message.is_empty() => "parameter not set".to_string(),
+                    message => message,
+                };
+                Err(format!("{}: {}", name, message))
+            }
+            '#' | '%' => {
+                let value = self.require_set(name, value)?;
+                let longest = word.starts_with(operator);
+                let word = if longest { &word[1..] } else { word };
+                let pattern = Pattern::from_segments(&self.expand_segments(word));
+                Ok(match operator {
+                    '#' => remove_prefix(&value, &pattern, longest),
+                    _ => remove_suffix(&value, &pattern, longest),
+                }
+                .to_string())
+            }
+            '/' => {
+                // Skip the `/`, `#` or `%` that says where the pattern can match
+                let marker = word.starts_with(['/', '#', '%']) as usize;
+                let (pattern, replacement) = match unquoted_slash(&word[marker..]) {
+                    Some(slash) => (&word[..marker + slash], &word[marker + slash + 1..]),
+                    None => (word, ""),
+                };
+                let value = self.require_set(name, value)?;
+                let replacement = self.perform_substitution(replacement);
+                Ok(self.replace_pattern(&value, pattern, &replacement))
+            }
+            '^' | ',' => {
+                let value = self.require_set(name, value)?;
+                let convert = |text: &str| match operator {
+                    '^' => text.to_uppercase(),
+                    _ => text.to_lowercase(),
+                };
+                Ok(match word.strip_prefix(operator) {
+                    Some(_) => convert(&value),
+                    None => {
+                        let first = value.chars().next().map_or(0, char::len_utf8);
+                        convert(&value[..first]) + &value[first..]
+                    }
+                })
+            }
+            ':' => {
+                let value = self.require_set(name, value)?;
+                self.substring(&value, word)
+                    .map_err(|err| format!("{}: {}", word, err))
+            }
+            _ => Err(bad_substitution()),
+        }
+    }
+
+    /// The value of a variable being expanded, which is an error if it isn't set and the `nounset`
+    /// option is on.
+    fn require_set(&self, name: &str, value: Option<String>) -> Result<String, String> {
+        match value {
+            Some(value) => Ok(value),
+            None if self.options().nounset => Err(format!("{}: unbound variable", name)),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Looks up `name[subscript]`. Lists are indexed by an arithmetic expression that counts from
+    /// the end if negative, maps by a key, and a string is its own element 0. `@` and `*` stand for
+    /// every value at once.
+    fn lookup_element(&mut self, name: &str, subscript: &str) -> Result<Option<String>, String> {
+        if subscript == "@" || subscript == "*" {
+            return Ok(self.lookup_variable(name));
+        }
+        if let Some(Variable::Map(_)) = self.variable(name) {
+            let key = self.perform_substitution(subscript);
+            return Ok(match self.variable(name) {
+                Some(Variable::Map(map)) => map.get(&key).cloned(),
+                _ => None,
+            });
+        }
+        let index = self
+            .expand_and_evaluate(subscript)
+            .map_err(|err| format!("{}: {}", subscript, err))?;
+        let values = match self.list_values(name) {
+            Some(values) => values,
+            None => self.lookup_variable(name).into_iter().collect(),
+        };
+        let index = if index < 0 {
+            index + values.len() as i64
+        } else {
+            index
+        };
+        Ok(usize::try_from(index)
+            .ok()
+            .and_then(|index| values.get(index).cloned()))
+    }
+
+    /// Looks up a reference to a list following a `$`, returning its values and what follows the
+    /// reference. That's `@`, a list or map variable, `${name[@]}`, or `${!name[@]}` for the keys.
+    fn take_list_reference<'a>(&self, source: &'a str) -> Option<(Vec<String>, &'a str)> {
+        let Some(braced) = source.strip_prefix('{') else {
+            let (name, rest) = take_name(source)?;
+            return Some((self.list_values(name)?, rest));
+        };
+        let end = closing_brace(braced)?;
+        let (inner, rest) = (&braced[..end], &braced[end + 1..]);
+        if let Some(name) = inner.strip_prefix('!') {
+            return Some((self.list_keys(name.strip_suffix("[@]")?)?, rest));
+        }
+        match inner.strip_suffix("[@]") {
+            Some(name) => match self.list_values(name) {
+                Some(values) => Some((values, rest)),
+                // A string is a list of just itself
+                None => Some((self.lookup_variable(name).into_iter().collect(), rest)),
+            },
+            None => Some((self.list_values(inner)?, rest)),
+        }
+    }
+
+    /// Replaces the first match of a pattern, or every match with `//`. A pattern starting with
+    /// `#` or `%` only matches at the start or end of the value.
+    fn replace_pattern(&mut self, value: &str, pattern: &str, replacement: &str) -> String {
+        let (all, anchor, pattern) = match pattern.chars().next() {
+            Some('/') => (true, None, &pattern[1..]),
+            Some(anchor @ ('#' | '%')) => (false, Some(anchor), &pattern[1..]),
+            _ => (false, None, pattern),
+        };
+        let pattern = Pattern::from_segments(&self.expand_segments(pattern));
+        match anchor {
+            Some('#') => {
+                let rest = remove_prefix(value, &pattern, true);
+                if rest.len() == value.len() {
+                    return value.to_string();
+                }
+                return format!("{}{}", replacement, rest);
+            }
+            Some(_) => {
+                let rest = remove_suffix(value, &pattern, true);
+                if rest.len() == value.len() {
+                    return value.to_string();
+                }
+                return format!("{}{}", rest, replacement);
+            }
+            None => {}
+        }
+        let mut result = String::new();
+        let mut rest = value;
+        while !rest.is_empty() {
+            // The longest match starting at the current position wins
+            let matched = boundaries(rest)
+                .rev()
+                .find(|end| *end > 0 && pattern.matches(&rest[..*end]));
+            match matched {
+                Some(end) => {
+                    result.push_str(replacement);
+                    rest = &rest[end..];
+                    if !all {
+                        break;
+                    }
+                }
+                None => {
+                    let c = rest.chars().next().unwrap();
+                    result.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        result + rest
+    }
+
+    fn expand_and_evaluate(&mut self, expression: &str) -> Result<i64, ArithError> {
+        let expression = self.perform_heredoc_substitution(expression);
+        self.evaluate_arithmetic(&expression)
+    }
+
+    /// Takes the characters of `value` described by `offset[:length]`, where both are arithmetic
+    /// expressions. A negative offset counts from the end, as does a negative length.
+    fn substring(&mut self, value: &str, range: &str) -> Result<String, ArithError> {
+        let (offset, length) = match range.split_once(':') {
+            Some((offset, length)) => (offset, Some(length)),
+            None => (range, None),
+        };
+        let count = value.chars().count() as i64;
+        let offset = self.expand_and_evaluate(offset)?;
+        let start = if offset < 0 { count + offset } else { offset }.clamp(0, count);
+        let end = match length {
+            Some(length) => {
+                let length = self.expand_and_evaluate(length)?;
+                if length < 0 {
+                    count + length
+                } else {
+                    start + length
+                }
+            }
+            None => count,
+        }
+        .clamp(start, count);
+        Ok(value
+            .chars()
+            .skip(start as usize)
+            .take((end - start) as usize)
+            .collect())
+    }
+
+    /// Expands the legacy form of command substitution following a backtick. Inside the
+    /// backticks, a backslash only escapes `$`, `` ` `` and `\`.
+    fn expand_backticks<'a>(&mut self, source: &'a str) -> (String, &'a str) {
+        let Some(end) = closing_backtick(source) else {
+            return ("`".to_string(), source);
+        };
+        let mut command = String::new();
+        let mut chars = source[..end].chars().peekable();
+        while let Some(c) = chars.next() {
+            match chars.peek() {
+                Some(escaped) if c == '\\' && BACKTICK_ESCAPES.contains(escaped) => {
+                    command.extend(chars.next())
+                }
+                _ => command.push(c),
+            }
+        }
+        (self.substitute_command(&command), &source[end + 1..])
+    }
+
+    /// Evaluates the expression inside `$(( ))`, after substituting any parameters and commands in
+    /// it. An invalid expression fails the expansion.
+    fn expand_arithmetic(&mut self, expression: &str) -> String {
+        let expression = self.perform_heredoc_substitution(expression);
+        match self.evaluate_arithmetic(&expression) {
+            Ok(value) => value.to_string(),
+            Err(err) => {
+                self.fail_expansion(format!("{}: {}", expression.trim(), err));
+                String::new()
+            }
+        }
+    }
+
+    /// Runs a command line in a subshell and returns what it writes to stdout, minus any trailing
+    /// newlines. The subshell's exit status becomes `$?`.
+    fn substitute_command(&mut self, command: &str) -> String {
+        let (mut reader, writer) = match io::pipe() {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                eprintln!("dcsh: pipe: {}", describe_io_error(&err));
+                self.set_substitution_status(1);
+                return String::new();
+            }
+        };
+        let forked = process::fork(|| {
+            let mut redirections = Redirections::new();
+            if let Err(err) = redirections.connect(1, writer) {
+                eprintln!("dcsh: {}", err);
+                return 1;
+            }
+            self.exec_command(command.to_string())
+        });
+        let mut output = vec![];
+        let status = match forked {
+            Ok(process) => {
+                // The pipe only reaches the end once the parent's copy of the writer is closed
+                let _ = reader.read_to_end(&mut output);
+                process.wait()
+            }
+            Err(err) => {
+                eprintln!("dcsh: fork: {}", describe_io_error(&err));
+                1
+            }
+        };
+        self.set_substitution_status(status);
+        let output = String::from_utf8_lossy(&output);
+        output.trim_end_matches('\n').to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quoting() {
+        let mut context = ExecContext::new();
+        context.set_variable("x", "a b");
+        assert_eq!(context.perform_substitution("foo\"bar\"'baz'"), "foobarbaz");
+        assert_eq!(context.perform_substitution("'$x' \"$x\" $x"), "$x a b a b");
+        assert_eq!(
+            context.perform_substitution(r#"\$x \"\'\ \\"#),
+            r#"$x "' \"#
+        );
+        assert_eq!(
+            context.perform_substitution(r#""\$x \" \n \\""#),
+            r#"$x " \n \"#
+        );
+        assert_eq!(context.perform_substitution("${x}y $xy $"), "a by  $");
+        assert_eq!(context.perform_substitution("$1x"), "x");
+        assert_eq!(
+            context.expand_segments("a'b'\"\""),
+            vec![
+                Segment::new("a", false),
+                Segment::new("b", true),
+                Segment::new("", true)
+            ]
+        );
+        assert_eq!(
+            context.perform_heredoc_substitution("\"$x\" \\\" \\$x '$x'\n"),
+            "\"a b\" \\\" $x 'a b'\n"
+        );
+        assert_eq!(unquote(r#"'E'O"F\"" \$"#), r#"EOF" $"#);
+    }
+
+    #[test]
+    fn test_command_substitution() {
+        let mut context = ExecContext::new();
+        context.set_variable("x", "a b");
+        assert_eq!(
+            context.perform_substitution("[$(printf '%s\\n\\n' \"$x\")]"),
+            "[a b]"
+        );
+        assert_eq!(
+            context.perform_substitution("\"$(echo \"$(echo ')')\")\" `echo \\`echo \\\\$x\\``"),
+            ") a b"
+        );
+        context.perform_substitution("$(exit 3)");
+        assert_eq!(context.status(), 3);
+        assert_eq!(
+            context.perform_substitution("$(echo unclosed"),
+            "$(echo unclosed"
+        );
+        assert_eq!(word_length("a$(b c)\"$(d \")\")\"`e f` g"), Some(22));
+        assert_eq!(word_length("$((1 + 2)) x"), Some(10));
+        assert_eq!(word_length("'a"), None);
+        assert_eq!(
+            context.perform_substitution("$(( (2 + $(echo 3)) * 2 ))"),
+            "10"
+        );
+        context.set_variable("n", "4");
+        assert_eq!(
+            context.perform_substitution("\"$((n * $n))\"$((n += 1))"),
+            "165"
+        );
+        assert_eq!(context.get_variable("n"), Some("5"));
+        assert_eq!(context.perform_substitution("$((1 / 0))"), "");
+        assert_eq!(
+            context.take_expansion_error(),
+            Some("1 / 0: division by 0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_word_splitting() {
+        let mut context = ExecContext::new();
+        context.set_variable("x", " a  b\t");
+        context.set_variable("star", "*");
+        let arguments = ["1 2".to_string(), "".to_string(), "3".to_string()];
+        context.set_arguments("script", &arguments);
+        let mut expand = |word| context.expand_word(word);
+        assert_eq!(expand("$x"), vec!["a", "b"]);
+        assert_eq!(expand("\"$x\""), vec![" a  b\t"]);
+        assert_eq!(expand("<$x>"), vec!["<", "a", "b", ">"]);
+        assert_eq!(expand("$unset"), Vec::<String>::new());
+        assert_eq!(expand("\"\"$unset"), vec![""]);
+        assert_eq!(expand("$(printf 'a\\nb')"), vec!["a", "b"]);
+        assert_eq!(expand("\"$@\""), vec!["1 2", "", "3"]);
+        assert_eq!(expand("\"<$@>\""), vec!["<1 2", "", "3>"]);
+        assert_eq!(expand("$@"), vec!["1", "2", "3"]);
+        assert_eq!(expand("\"$*\""), vec!["1 2  3"]);
+        assert_eq!(expand("\"$star\""), vec!["*"]);
+        context.set_arguments("script", &[]);
+        assert_eq!(context.expand_word("\"$@\""), Vec::<String>::new());
+        assert_eq!(context.expand_word("\"x$@\""), vec!["x"]);
+    }
+
+    #[test]
+    fn test_collections() {
+        let mut context = ExecContext::new();
+        let source = "files = [a, \"b c\", $(echo d)]\ncfg = {x: 1, y: two words}\ni = 1\n";
+        context.execute(crate::parse::parse(source).unwrap());
+        let mut expand = |word| context.expand_word(word);
+        assert_eq!(expand("\"$files\""), vec!["a", "b c", "d"]);
+        assert_eq!(expand("$files"), vec!["a", "b", "c", "d"]);
+        assert_eq!(expand("\"<${files[@]}>\""), vec!["<a", "b c", "d>"]);
+        assert_eq!(expand("\"${files[*]}\""), vec!["a b c d"]);
+        assert_eq!(
+            expand("${files[i]}|${files[-1]}|${files[3]}"),
+            vec!["b", "c|d|"]
+        );
+        assert_eq!(expand("${#files}|${#files[@]}|${#files[1]}"), vec!["3|3|3"]);
+        assert_eq!(
+            expand("\"${cfg[y]}\" ${cfg[z]:-none} ${#cfg}"),
+            vec!["two words", "none", "2"]
+        );
+        assert_eq!(expand("\"${!cfg[@]}\""), vec!["x", "y"]);
+        assert_eq!(expand("${!files[@]}"), vec!["0", "1", "2"]);
+        assert_eq!(expand("${i[0]}|${i[1]}|\"${i[@]}\""), vec!["1||1"]);
+        let source =
+            "files += [e]\nfiles[0] = z\ncfg[$i] = one\ncfg += {x: 9}\ni += 0\nnew[0] = n\n";
+        context.execute(crate::parse::parse(source).unwrap());
+        assert_eq!(
+            context.expand_word("\"$files\""),
+            vec!["z", "b c", "d", "e"]
+        );
+        assert_eq!(
+            context.expand_word("\"$cfg\""),
+            vec!["one", "9", "two words"]
+        );
+        assert_eq!(context.expand_word("$i ${new[0]}"), vec!["10", "n"]);
+        context.execute(crate::parse::parse("files[9] = x\ncfg += [x]").unwrap());
+        assert_eq!(context.status(), 1);
+        assert_eq!(context.expand_word("\"$PIPESTATUS\""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_brace_expansion() {
+        assert_eq!(expand_braces("file{,.bak}"), vec!["file", "file.bak"]);
+        assert_eq!(
+            expand_braces("{a,b{1,2}}x{y,z}"),
+            vec!["axy", "axz", "b1xy", "b1xz", "b2xy", "b2xz"]
+        );
+        assert_eq!(expand_braces("{1..4}"), vec!["1", "2", "3", "4"]);
+        assert_eq!(expand_braces("{01..10..3}"), vec!["01", "04", "07", "10"]);
+        assert_eq!(expand_braces("{3..-1..2}"), vec!["3", "1", "-1"]);
+        assert_eq!(expand_braces("{c..a}"), vec!["c", "b", "a"]);
+        assert_eq!(expand_braces("{a}"), vec!["{a}"]);
+        assert_eq!(expand_braces("{a..3}"), vec!["{a..3}"]);
+        assert_eq!(
+            expand_braces("\"{a,b}\"\\{c,d}${x,y}"),
+            vec!["\"{a,b}\"\\{c,d}${x,y}"]
+        );
+        assert_eq!(expand_braces("{x,'a,b'}"), vec!["x", "'a,b'"]);
+        assert_eq!(expand_braces("{,}{"), vec!["{", "{"]);
+    }
+
+    #[test]
+    fn test_tilde_expansion() {
+        let mut context = ExecContext::new();
+        context.set_variable("HOME", "/home/me");
+        context.set_variable("PWD", "/here");
+        assert_eq!(context.perform_substitution("~/src"), "/home/me/src");
+        assert_eq!(context.perform_substitution("~+"), "/here");
+        assert_eq!(context.perform_substitution("~-"), "~-");
+        let root = home_directory("root").unwrap();
+        assert_eq!(
+            context.perform_substitution("~root/x"),
+            format!("{}/x", root)
+        );
+        assert_eq!(
+            context.perform_substitution("~no-such-user"),
+            "~no-such-user"
+        );
+        assert_eq!(context.perform_substitution("\"~\" a~ ~\"me\""), "~ a~ ~me");
+        context.set_variable("HOME", "/a b/*");
+        assert_eq!(context.expand_word("~"), vec!["/a b/*"]);
+    }
+
+    #[test]
+    fn test_parameter_expansion() {
+        let mut context = ExecContext::new();
+        context.set_variable("path", "/usr/local/lib.tar.gz");
+        context.set_variable("empty", "");
+        context.set_variable("word", "héllo world");
+        let mut expand = |word| context.perform_substitution(word);
+        assert_eq!(expand("${#word} ${#unset}"), "11 0");
+        assert_eq!(
+            expand("${unset:-a b}|${empty:-$path}|${empty-x}"),
+            "a b|/usr/local/lib.tar.gz|"
+        );
+        assert_eq!(
+            expand("${word:+alt}|${empty:+alt}|${empty+alt}"),
+            "alt||alt"
+        );
+        assert_eq!(
+            expand("${path#*/}|${path##*/}"),
+            "usr/local/lib.tar.gz|lib.tar.gz"
+        );
+        assert_eq!(
+            expand("${path%.*}|${path%%.*}"),
+            "/usr/local/lib.tar|/usr/local/lib"
+        );
+        assert_eq!(
+            expand("${path#\"*\"/}|${path%[.]gz}"),
+            "/usr/local/lib.tar.gz|/usr/local/lib.tar"
+        );
+        assert_eq!(
+            expand("${word/l/L}|${word//l/L}|${word//o}"),
+            "héLlo world|héLLo worLd|héll wrld"
+        );
+        assert_eq!(
+            expand("${word/#h?/X}|${word/%l*/X}|${word/#l/X}"),
+            "Xllo world|héX|héllo world"
+        );
+        assert_eq!(expand("${path//\\//:}"), ":usr:local:lib.tar.gz");
+        assert_eq!(
+            expand("${word^}|${word^^}|${path,,}"),
+            "Héllo world|HÉLLO WORLD|/usr/local/lib.tar.gz"
+        );
+        assert_eq!(
+            expand("${word:1:3}|${word: -5}|${word:6:-2}|${word:(1+1)}"),
+            "éll|world|wor|llo world"
+        );
+        assert_eq!(expand("${new:=set}|$new"), "set|set");
+        assert_eq!(expand("${unset:?}"), "");
+        assert_eq!(
+            context.take_expansion_error(),
+            Some("unset: parameter not set".to_string())
+        );
+        assert_eq!(context.perform_substitution("${word!}"), "");
+        assert_eq!(
+            context.take_expansion_error(),
+            Some("${word!}: bad substitution".to_string())
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::{fs, path::Path};
+
+use crate::pattern::Pattern;
+
+/// Expands a glob pattern into the sorted paths that match it. Each `/`-separated component of the
+/// pattern matches names within the directories matched so far, and a component that is just `**`
+/// matches any number of nested directories. Names starting with `.` are only matched by a
+/// component that starts with `.` too, unless `dotglob` is set.
+pub fn glob(pattern: &str, dotglob: bool) -> Vec<String> {
+    let root = if pattern.starts_with('/') { "/" } else { "" };
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let mut paths = vec![root.to_string()];
+    for (index, component) in components.iter().enumerate() {
+        let last = index == components.len() - 1;
+        paths = paths
+            .iter()
+            .flat_map(|path| match *component {
+                "**" => descendants(path, dotglob, last),
+                component => matching_children(path, component, dotglob),
+            })
+            .collect();
+    }
+    if components.is_empty() {
+        return vec![];
+    }
+    if pattern.ends_with('/') {
+        // A trailing slash only matches directories
+        paths.retain(|path| Path::new(path).is_dir());
+        paths.iter_mut().for_each(|path| path.push('/'));
+    }
+    paths.sort();
+    paths
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() || path.ends_with('/') {
+        format!("{}{}", path, name)
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+/// The names in a directory, or nothing if it can't be read.
+fn children(path: &str) -> Vec<String> {
+    let directory = if path.is_empty() { "." } else { path };
+    let Ok(entries) = fs::read_dir(directory) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect()
+}
+
+fn matching_children(path: &str, component: &str, dotglob: bool) -> Vec<String> {
+    let pattern = Pattern::new(component);
+    if let Some(name) = pattern.literal() {
+        let joined = join(path, &name);
+        return match Path::new(&joined).symlink_metadata() {
+            Ok(_) => vec![joined],
+            Err(_) => vec![],
+        };
+    }
+    let hidden_allowed = dotglob || component.starts_with('.');
+    children(path)
+        .into_iter()
+        .filter(|name| hidden_allowed || !name.starts_with('.'))
+        .filter(|name| pattern.matches(name))
+        .map(|name| join(path, &name))
+        .collect()
+}
+
+/// Everything `**` matches beneath `path`. In the middle of a pattern that is `path` itself and
+/// every directory beneath it, while at the end it is every file and directory beneath it.
+/// Symbolic links to directories are not followed, so that a link to a parent can't loop forever.
+fn descendants(path: &str, dotglob: bool, last: bool) -> Vec<String> {
+    let mut found = if last { vec![] } else { vec![path.to_string()] };
+    for name in children(path) {
+        if !dotglob && name.starts_with('.') {
+            continue;
+        }
+        let child = join(path, &name);
+        let is_dir = Path::new(&child)
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_dir());
+        if is_dir {
+            found.extend(descendants(&child, dotglob, last));
+            if last {
+                found.push(child);
+            }
+        } else if last {
+            found.push(child);
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        let root = std::env::temp_dir().join(format!("dcsh-glob-{}", std::process::id()));
+        for dir in ["a/b/c", "a/.hidden", "d"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "x.rs",
+            "y.rs",
+            ".z.rs",
+            "a/b/w.rs",
+            "a/b/c/v.rs",
+            "a/.hidden/u.rs",
+            "d/t",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let root = root.to_str().unwrap();
+        let glob = |pattern: &str, dotglob| {
+            let paths = super::glob(&format!("{}/{}", root, pattern), dotglob);
+            let prefix = format!("{}/", root);
+            paths
+                .into_iter()
+                .map(|path| path.strip_prefix(&prefix).unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(glob("*.rs", false), vec!["x.rs", "y.rs"]);
+        assert_eq!(glob("*.rs", true), vec![".z.rs", "x.rs", "y.rs"]);
+        assert_eq!(glob(".*", false), vec![".z.rs"]);
+        assert_eq!(glob("[a-c]/?/*", false), vec!["a/b/c", "a/b/w.rs"]);
+        assert_eq!(glob("*/", false), vec!["a/", "d/"]);
+        assert_eq!(
+            glob("**/*.rs", false),
+            vec!["a/b/c/v.rs", "a/b/w.rs", "x.rs", "y.rs"]
+        );
+        assert_eq!(
+            glob("a/**", false),
+            vec!["a/b", "a/b/c", "a/b/c/v.rs", "a/b/w.rs"]
+        );
+        assert_eq!(glob("a/**/u.rs", true), vec!["a/.hidden/u.rs"]);
+        assert_eq!(glob("d/t", false), vec!["d/t"]);
+        assert_eq!(glob("*.txt", false), Vec::<String>::new());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,16 +1,102 @@
+mod arith;
+mod builtin;
 mod command;
 mod exec;
+mod expand;
+mod glob;
 mod parse;
+mod pattern;
+mod process;
+mod redirect;
 mod terminal;
 
+use std::{
+    fs,
+    io::{stdin, IsTerminal, Read},
+};
+
 use exec::ExecContext;
 
 use parse::parse;
 
 fn main() -> crossterm::Result<()> {
-    terminal::setup()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
     let mut context = ExecContext::new();
+    context.import_environment();
+    // Leading options like `-e` or `-o pipefail` work as they do for `set`
+    let args = match context.options_mut().apply(&args) {
+        Ok(count) => &args[count..],
+        Err(message) => {
+            eprintln!("dcsh: {}", message);
+            std::process::exit(2);
+        }
+    };
+    match args.split_first() {
+        Some((flag, arguments)) if flag == "-c" => {
+            let status = run_command_string(&mut context, arguments);
+            std::process::exit(status);
+        }
+        Some((script, arguments)) => {
+            let status = run_script(&mut context, script, arguments);
+            std::process::exit(status);
+        }
+        None if !stdin().is_terminal() => {
+            let status = run_stdin(&mut context);
+            std::process::exit(status);
+        }
+        None => {}
+    }
+    context.set_interactive(true);
+    terminal::setup()?;
     terminal::event_loop(&mut context)?;
     terminal::teardown()?;
-    Ok(())
+    std::process::exit(context.status());
+}
+
+/// Runs a script file non-interactively, returning the status of the last command.
+fn run_script(context: &mut ExecContext, script: &str, arguments: &[String]) -> i32 {
+    let source = match fs::read_to_string(script) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("dcsh: {}: {}", script, command::describe_io_error(&err));
+            return 127;
+        }
+    };
+    context.set_arguments(script, arguments);
+    run_source(context, &source)
+}
+
+/// Runs the commands given to `-c`. As in other shells, any further arguments set `$0`, `$1`, ...
+fn run_command_string(context: &mut ExecContext, arguments: &[String]) -> i32 {
+    let Some((source, arguments)) = arguments.split_first() else {
+        eprintln!("dcsh: -c: option requires an argument");
+        return 2;
+    };
+    if let Some((name, arguments)) = arguments.split_first() {
+        context.set_arguments(name, arguments);
+    } else {
+        context.set_arguments("dcsh", &[]);
+    }
+    run_source(context, source)
+}
+
+/// Runs commands piped into the shell when stdin is not a terminal.
+fn run_stdin(context: &mut ExecContext) -> i32 {
+    let mut source = String::new();
+    if let Err(err) = stdin().read_to_string(&mut source) {
+        eprintln!("dcsh: failed to read stdin: {}", err);
+        return 1;
+    }
+    context.set_arguments("dcsh", &[]);
+    run_source(context, &source)
+}
+
+fn run_source(context: &mut ExecContext, source: &str) -> i32 {
+    if let Some(ast) = parse(source) {
+        context.execute(ast);
+        context.status()
+    } else {
+        eprintln!("dcsh: invalid syntax");
+        2
+    }
 }
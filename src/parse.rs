@@ -1,28 +1,127 @@
+use std::fmt;
+
 use lazy_static::lazy_static;
-use regex::Regex;
+use regex::{Captures, Regex};
+
+use crate::{command::read_heredocs, expand::split_unquoted};
 
 lazy_static! {
-    static ref RE_ASSIGNMENT: Regex = Regex::new("\\A([a-zA-Z0-9]+)[ \t]*=[ \t]*([^\n]*)\n").unwrap();
+    static ref RE_ASSIGNMENT: Regex = Regex::new("\\A([a-zA-Z0-9_]+)(?:\\[([^\\]\n]*)\\])?[ \t]+(\\+?=)[ \t]*([^\n]*)\n").unwrap();
     static ref RE_COMMAND: Regex = Regex::new("\\A([^\n]+)\n").unwrap();
-    static ref RE_IF: Regex = Regex::new("\\Aif[ \t]+([^:]+):[ \t]*\n").unwrap();
-    static ref RE_ELIF: Regex = Regex::new("\\Aelif[ \t]+([^:]+):[ \t]*\n").unwrap();
+    static ref RE_IF: Regex = Regex::new("\\Aif[ \t]+([^\n]+):[ \t]*\n").unwrap();
+    static ref RE_ELIF: Regex = Regex::new("\\Aelif[ \t]+([^\n]+):[ \t]*\n").unwrap();
+    static ref RE_WHILE: Regex = Regex::new("\\Awhile[ \t]+([^\n]+):[ \t]*\n").unwrap();
+    static ref RE_FOR: Regex = Regex::new("\\Afor[ \t]+([a-zA-Z_][a-zA-Z0-9_]*)[ \t]+in([ \t][^\n]*)?:[ \t]*\n").unwrap();
+    static ref RE_FUNCTION: Regex = Regex::new("\\A(?:def|fn)[ \t]+([a-zA-Z_][a-zA-Z0-9_-]*)[ \t]*\\(([^)\n]*)\\)[ \t]*:[ \t]*\n").unwrap();
+    static ref RE_PARAMETER: Regex = Regex::new("\\A[a-zA-Z_][a-zA-Z0-9_]*\\z").unwrap();
+    /// The header of a block statement, which can't be run as a command if it is malformed.
+    static ref RE_BLOCK_HEADER: Regex = Regex::new("\\A(?:(?:if|elif|while|for|def|fn|match)[ \t][^\n]*|else|try|except|finally)[ \t]*:[ \t]*\n").unwrap();
+    static ref RE_MATCH: Regex = Regex::new("\\Amatch[ \t]+([^\n]*):[ \t]*\n").unwrap();
+    static ref RE_ARM: Regex = Regex::new("\\A([^\n]*):[ \t]*\n").unwrap();
+    static ref RE_TRY: Regex = Regex::new("\\Atry[ \t]*:[ \t]*\n").unwrap();
+    static ref RE_EXCEPT: Regex = Regex::new("\\Aexcept[ \t]*:[ \t]*\n").unwrap();
+    static ref RE_FINALLY: Regex = Regex::new("\\Afinally[ \t]*:[ \t]*\n").unwrap();
     static ref RE_ELSE: Regex = Regex::new("\\Aelse[ \t]*:[ \t]*\n").unwrap();
     static ref RE_INDENTATION: Regex = Regex::new("\\A[ \t]*").unwrap();
-    static ref RE_EMPTY_LINES: Regex = Regex::new("\\A([ \t]*\n)*").unwrap();
+    static ref RE_EMPTY_LINES: Regex = Regex::new("\\A([ \t]*(#[^\n]*)?\n)*").unwrap();
     // static ref RE_IF: Regex = Regex::new("if ([^:]+):\n").unwrap();
 }
 
-#[derive(Debug)]
+/// The right hand side of an assignment, before it is expanded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    /// `[a, b, c]`
+    List(Vec<String>),
+    /// `{key: value, ...}`
+    Map(Vec<(String, String)>),
+}
+
+/// `name = value`, `name += value`, or `name[index] = value` for an element of a list or map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub variable: String,
+    pub index: Option<String>,
+    pub append: bool,
+    pub value: Value,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(value) => write!(f, "{}", value),
+            Value::List(items) => write!(f, "[{}]", items.join(", ")),
+            Value::Map(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Assignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.variable)?;
+        if let Some(index) = &self.index {
+            write!(f, "[{}]", index)?;
+        }
+        let operator = if self.append { "+=" } else { "=" };
+        write!(f, " {} {}", operator, self.value)
+    }
+}
+
+/// `def name(parameters):` followed by the body of the function. The parameters are local
+/// variables holding the first few arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub body: Vec<Statement>,
+}
+
+/// What the value of a `match` statement is compared against to choose an arm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArmPattern {
+    /// A shell pattern like `*.tar.gz`, which has to match the whole value.
+    Glob(String),
+    /// `/regex/`, which only has to match part of the value.
+    Regex(String),
+    /// `_`, which matches anything.
+    Default,
+}
+
+/// One arm of a `match` statement, which runs if any of its `|`-separated patterns match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arm {
+    pub patterns: Vec<ArmPattern>,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Statement {
-    Assignment(String, String),
+    Assignment(Assignment),
     Command(String),
     If(String, Vec<Statement>, Vec<Statement>),
+    While(String, Vec<Statement>),
+    /// `for variable in items:`, where the items are expanded like the arguments of a command.
+    For(String, String, Vec<Statement>),
+    Function(Function),
+    Match(String, Vec<Arm>),
+    /// `try:` with its `except:` and `finally:` blocks, either of which may be empty if left out.
+    Try(Vec<Statement>, Vec<Statement>, Vec<Statement>),
 }
 
 pub fn parse(source: &str) -> Option<Vec<Statement>> {
     let source = &format!("{}\n", source);
     // TODO parse out \ line continuations at this point
-    parse_code_block(source, "").map(|(statements, _source)| statements)
+    match parse_code_block(source, "")? {
+        // Anything left over is indented when it shouldn't be
+        (statements, "") => Some(statements),
+        _ => None,
+    }
 }
 
 fn parse_code_block<'a>(
@@ -30,7 +129,12 @@ fn parse_code_block<'a>(
     indentation: &'a str,
 ) -> Option<(Vec<Statement>, &'a str)> {
     let mut statements = vec![];
-    while !source.is_empty() {
+    loop {
+        let empty_lines = RE_EMPTY_LINES.find(source).unwrap().as_str();
+        source = &source[empty_lines.len()..source.len()];
+        if source.is_empty() {
+            break;
+        }
         let actual_indentation = RE_INDENTATION.find(source).unwrap().as_str();
         if actual_indentation != indentation {
             return Some((statements, source));
@@ -42,8 +146,6 @@ fn parse_code_block<'a>(
         } else {
             return None;
         }
-        let empty_lines = RE_EMPTY_LINES.find(source).unwrap().as_str();
-        source = &source[empty_lines.len()..source.len()];
     }
     Some((statements, source))
 }
@@ -51,6 +153,18 @@ fn parse_code_block<'a>(
 fn parse_statement<'a>(source: &'a str, indentation: &'a str) -> Option<(Statement, &'a str)> {
     if let Some((statement, remainder)) = parse_if(source, indentation) {
         Some((statement, remainder))
+    } else if let Some((statement, remainder)) = parse_while(source, indentation) {
+        Some((statement, remainder))
+    } else if let Some((statement, remainder)) = parse_for(source, indentation) {
+        Some((statement, remainder))
+    } else if let Some((statement, remainder)) = parse_function(source, indentation) {
+        Some((statement, remainder))
+    } else if let Some((statement, remainder)) = parse_match(source, indentation) {
+        Some((statement, remainder))
+    } else if let Some((statement, remainder)) = parse_try(source, indentation) {
+        Some((statement, remainder))
+    } else if RE_BLOCK_HEADER.is_match(source) {
+        None
     } else if let Some((statement, remainder)) = parse_assignment(source) {
         Some((statement, remainder))
     } else if let Some((statement, remainder)) = parse_command(source) {
@@ -63,50 +177,559 @@ fn parse_statement<'a>(source: &'a str, indentation: &'a str) -> Option<(Stateme
 fn parse_assignment(source: &str) -> Option<(Statement, &str)> {
     if let Some(captures) = RE_ASSIGNMENT.captures(source) {
         let variable = captures.get(1).unwrap().as_str().to_string();
-        let expression = captures.get(2).unwrap().as_str().to_string();
-        let assignment = Statement::Assignment(variable, expression);
+        let index = captures.get(2).map(|index| index.as_str().to_string());
+        let operator = captures.get(3).unwrap().as_str();
+        let expression = captures.get(4).unwrap().as_str();
+        let assignment = Assignment {
+            variable,
+            index,
+            append: operator == "+=",
+            value: parse_value(expression),
+        };
         let remainder = &source[captures.get(0).unwrap().len()..source.len()];
-        Some((assignment, remainder))
+        Some((Statement::Assignment(assignment), remainder))
     } else {
         None
     }
 }
 
+/// Parses a list or map literal, or else a plain string. Items are separated by commas, and map
+/// keys by the first colon, unless the comma or colon is quoted. Something that only looks like a
+/// literal, such as `{a,b}`, is a plain string too.
+fn parse_value(expression: &str) -> Value {
+    parse_literal(expression.trim_end()).unwrap_or_else(|| Value::String(expression.to_string()))
+}
+
+fn parse_literal(literal: &str) -> Option<Value> {
+    let items = |inner: &str| -> Option<Vec<String>> {
+        let mut items: Vec<String> = split_unquoted(inner, ',')?
+            .into_iter()
+            .map(|item| item.trim().to_string())
+            .collect();
+        // Allow a trailing comma, and an empty literal
+        if items.last().is_some_and(String::is_empty) {
+            items.pop();
+        }
+        Some(items)
+    };
+    if let Some(inner) = literal
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+    {
+        return items(inner).map(Value::List);
+    }
+    let inner = literal
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))?;
+    let mut entries = vec![];
+    for item in items(inner)? {
+        // Only the first colon separates the key, so that values like URLs can have colons
+        let [key, _, ..] = split_unquoted(&item, ':')?[..] else {
+            return None;
+        };
+        let value = &item[key.len() + 1..];
+        entries.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    Some(Value::Map(entries))
+}
+
 fn parse_command(source: &str) -> Option<(Statement, &str)> {
     if let Some(captures) = RE_COMMAND.captures(source) {
-        let expression = captures.get(1).unwrap().as_str().to_string();
+        let line = captures.get(1).unwrap().as_str();
+        let line_length = captures.get(0).unwrap().len();
+        // Here-document bodies on the following lines belong to the command too
+        let (_, heredoc_length) = read_heredocs(line, &source[line_length..]);
+        let end = line_length + heredoc_length;
+        let expression = source[0..end].trim_end_matches('\n').to_string();
         let command = Statement::Command(expression);
-        let remainder = &source[captures.get(0).unwrap().len()..source.len()];
+        let remainder = &source[end..source.len()];
         Some((command, remainder))
     } else {
         None
     }
 }
 
-fn parse_if<'a>(source: &'a str, _indentation: &'a str) -> Option<(Statement, &'a str)> {
+fn parse_if<'a>(source: &'a str, indentation: &'a str) -> Option<(Statement, &'a str)> {
     if let Some(captures) = RE_IF.captures(source) {
-        let condition = captures.get(1).unwrap().as_str().to_string();
-        let remainder = &source[captures.get(0).unwrap().len()..source.len()];
-        let new_indentation = find_indentation(remainder);
-        // TODO ensure new_indentation is longer than old
-        let (if_code, remainder) = parse_code_block(remainder, new_indentation).unwrap();
-        if if_code.is_empty() {
+        parse_conditional(source, indentation, captures)
+    } else {
+        None
+    }
+}
+
+fn parse_while<'a>(source: &'a str, indentation: &'a str) -> Option<(Statement, &'a str)> {
+    let captures = RE_WHILE.captures(source)?;
+    let condition = captures.get(1).unwrap().as_str().trim_end().to_string();
+    let remainder = &source[captures.get(0).unwrap().len()..source.len()];
+    let (body, remainder) = parse_indented_block(remainder, indentation)?;
+    Some((Statement::While(condition, body), remainder))
+}
+
+fn parse_for<'a>(source: &'a str, indentation: &'a str) -> Option<(Statement, &'a str)> {
+    let captures = RE_FOR.captures(source)?;
+    let variable = captures.get(1).unwrap().as_str().to_string();
+    let items = captures.get(2).map_or("", |items| items.as_str().trim());
+    let remainder = &source[captures.get(0).unwrap().len()..source.len()];
+    let (body, remainder) = parse_indented_block(remainder, indentation)?;
+    Some((Statement::For(variable, items.to_string(), body), remainder))
+}
+
+fn parse_function<'a>(source: &'a str, indentation: &'a str) -> Option<(Statement, &'a str)> {
+    let captures = RE_FUNCTION.captures(source)?;
+    let name = captures.get(1).unwrap().as_str().to_string();
+    let mut parameters = vec![];
+    for parameter in captures.get(2).unwrap().as_str().split(',') {
+        let parameter = parameter.trim();
+        if RE_PARAMETER.is_match(parameter) {
+            parameters.push(parameter.to_string());
+        } else if !parameter.is_empty() || !parameters.is_empty() {
             return None;
         }
-        if let Some(_captures) = RE_ELIF.captures(remainder) {
-            // TODO elif
-        } else if let Some(_captures) = RE_ELSE.captures(remainder) {
-            // TODO else
+    }
+    let remainder = &source[captures.get(0).unwrap().len()..source.len()];
+    let (body, remainder) = parse_indented_block(remainder, indentation)?;
+    let function = Function {
+        name,
+        parameters,
+        body,
+    };
+    Some((Statement::Function(function), remainder))
+}
+
+fn parse_match<'a>(source: &'a str, indentation: &'a str) -> Option<(Statement, &'a str)> {
+    let captures = RE_MATCH.captures(source)?;
+    let value = captures.get(1).unwrap().as_str().trim().to_string();
+    let mut remainder = &source[captures.get(0).unwrap().len()..source.len()];
+    let empty_lines = RE_EMPTY_LINES.find(remainder).unwrap().as_str();
+    let arm_indentation = find_indentation(&remainder[empty_lines.len()..]);
+    if arm_indentation.len() <= indentation.len() || !arm_indentation.starts_with(indentation) {
+        return None;
+    }
+    let mut arms = vec![];
+    loop {
+        let empty_lines = RE_EMPTY_LINES.find(remainder).unwrap().as_str();
+        let line = &remainder[empty_lines.len()..];
+        if line.is_empty() || find_indentation(line) != arm_indentation {
+            break;
+        }
+        let captures = RE_ARM.captures(&line[arm_indentation.len()..])?;
+        let patterns = parse_arm_patterns(captures.get(1).unwrap().as_str().trim())?;
+        let rest = &line[arm_indentation.len() + captures.get(0).unwrap().len()..];
+        let (body, rest) = parse_indented_block(rest, arm_indentation)?;
+        arms.push(Arm { patterns, body });
+        remainder = rest;
+    }
+    Some((Statement::Match(value, arms), remainder))
+}
+
+/// Parses the patterns of a `match` arm. A regex takes up the whole arm, since it may contain `|`
+/// itself.
+fn parse_arm_patterns(source: &str) -> Option<Vec<ArmPattern>> {
+    if let Some(regex) = source
+        .strip_prefix('/')
+        .and_then(|rest| rest.strip_suffix('/'))
+    {
+        return Some(vec![ArmPattern::Regex(regex.to_string())]);
+    }
+    split_unquoted(source, '|')?
+        .into_iter()
+        .map(|pattern| match pattern.trim() {
+            "" => None,
+            "_" => Some(ArmPattern::Default),
+            pattern => Some(ArmPattern::Glob(pattern.to_string())),
+        })
+        .collect()
+}
+
+/// Parses a `try` block along with the `except` and `finally` blocks after it, at least one of
+/// which is required.
+fn parse_try<'a>(source: &'a str, indentation: &'a str) -> Option<(Statement, &'a str)> {
+    let captures = RE_TRY.captures(source)?;
+    let remainder = &source[captures.get(0).unwrap().len()..source.len()];
+    let (body, remainder) = parse_indented_block(remainder, indentation)?;
+    let (handler, remainder) = parse_clause(remainder, indentation, &RE_EXCEPT)?;
+    let (cleanup, remainder) = parse_clause(remainder, indentation, &RE_FINALLY)?;
+    if handler.is_empty() && cleanup.is_empty() {
+        return None;
+    }
+    Some((Statement::Try(body, handler, cleanup), remainder))
+}
+
+/// Parses a clause like `finally:` following another block at the same indentation, or nothing if
+/// the next line isn't one.
+fn parse_clause<'a>(
+    source: &'a str,
+    indentation: &'a str,
+    header: &Regex,
+) -> Option<(Vec<Statement>, &'a str)> {
+    let empty_lines = RE_EMPTY_LINES.find(source).unwrap().as_str();
+    let line = match source[empty_lines.len()..].strip_prefix(indentation) {
+        Some(line) if find_indentation(line).is_empty() => line,
+        _ => return Some((vec![], source)),
+    };
+    match header.captures(line) {
+        Some(captures) => {
+            parse_indented_block(&line[captures.get(0).unwrap().len()..], indentation)
         }
-        let else_code = vec![];
-        let if_statement = Statement::If(condition, if_code, else_code);
-        Some((if_statement, remainder))
+        None => Some((vec![], source)),
+    }
+}
+
+/// Parses the body of an `if` or `elif` whose header has already been matched, along with any
+/// `elif` or `else` clauses that follow it at the same indentation.
+fn parse_conditional<'a>(
+    source: &'a str,
+    indentation: &'a str,
+    captures: Captures,
+) -> Option<(Statement, &'a str)> {
+    let condition = captures.get(1).unwrap().as_str().trim_end().to_string();
+    let remainder = &source[captures.get(0).unwrap().len()..source.len()];
+    let (if_code, remainder) = parse_indented_block(remainder, indentation)?;
+    let (else_code, remainder) = parse_else(remainder, indentation)?;
+    let if_statement = Statement::If(condition, if_code, else_code);
+    Some((if_statement, remainder))
+}
+
+fn parse_else<'a>(source: &'a str, indentation: &'a str) -> Option<(Vec<Statement>, &'a str)> {
+    let line = match source.strip_prefix(indentation) {
+        Some(line) if find_indentation(line).is_empty() => line,
+        _ => return Some((vec![], source)),
+    };
+    if let Some(captures) = RE_ELIF.captures(line) {
+        // An elif is just an if nested inside the else block
+        let (elif_statement, remainder) = parse_conditional(line, indentation, captures)?;
+        Some((vec![elif_statement], remainder))
+    } else if let Some(captures) = RE_ELSE.captures(line) {
+        let remainder = &line[captures.get(0).unwrap().len()..line.len()];
+        parse_indented_block(remainder, indentation)
     } else {
-        None
+        Some((vec![], source))
     }
 }
 
+/// Parses a non-empty block of code that is indented further than the enclosing block.
+fn parse_indented_block<'a>(
+    source: &'a str,
+    indentation: &'a str,
+) -> Option<(Vec<Statement>, &'a str)> {
+    let empty_lines = RE_EMPTY_LINES.find(source).unwrap().as_str();
+    let source = &source[empty_lines.len()..source.len()];
+    let new_indentation = find_indentation(source);
+    if new_indentation.len() <= indentation.len() || !new_indentation.starts_with(indentation) {
+        return None;
+    }
+    let (code, remainder) = parse_code_block(source, new_indentation)?;
+    if code.is_empty() {
+        return None;
+    }
+    Some((code, remainder))
+}
+
 fn find_indentation(source: &str) -> &str {
     let captures = RE_INDENTATION.captures(source).unwrap();
     captures.get(0).unwrap().as_str()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command(command: &str) -> Statement {
+        Statement::Command(command.to_string())
+    }
+
+    fn assign(variable: &str, index: Option<&str>, append: bool, value: Value) -> Statement {
+        Statement::Assignment(Assignment {
+            variable: variable.to_string(),
+            index: index.map(str::to_string),
+            append,
+            value,
+        })
+    }
+
+    fn string(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(
+            parse("x = hello world\ny =1"),
+            Some(vec![
+                assign("x", None, false, string("hello world")),
+                assign("y", None, false, string("1")),
+            ])
+        );
+        // Without a blank before the `=`, the variable is only set for the command after it
+        assert_eq!(
+            parse("FOO=1 make\nx=1\nFOO= make"),
+            Some(vec![
+                command("FOO=1 make"),
+                command("x=1"),
+                command("FOO= make")
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_collections() {
+        let list = |items: &[&str]| Value::List(items.iter().map(|s| s.to_string()).collect());
+        assert_eq!(
+            parse("files = [a, \"b, c\" ,$d,]\nempty = []\nfiles += [e]\nfiles[-1] = f\nx += y"),
+            Some(vec![
+                assign("files", None, false, list(&["a", "\"b, c\"", "$d"])),
+                assign("empty", None, false, list(&[])),
+                assign("files", None, true, list(&["e"])),
+                assign("files", Some("-1"), false, string("f")),
+                assign("x", None, true, string("y")),
+            ])
+        );
+        let entries = vec![
+            ("k".to_string(), "v".to_string()),
+            ("'a:b'".to_string(), "c d".to_string()),
+        ];
+        assert_eq!(
+            parse("cfg = {k: v, 'a:b' : c d}\ncfg[$key] = 1"),
+            Some(vec![
+                assign("cfg", None, false, Value::Map(entries)),
+                assign("cfg", Some("$key"), false, string("1")),
+            ])
+        );
+        assert_eq!(
+            parse("x = {a,b}\ny = [\"a]\nz = {a: b: c, url: http://example.com}"),
+            Some(vec![
+                assign("x", None, false, string("{a,b}")),
+                assign("y", None, false, string("[\"a]")),
+                assign(
+                    "z",
+                    None,
+                    false,
+                    Value::Map(vec![
+                        ("a".to_string(), "b: c".to_string()),
+                        ("url".to_string(), "http://example.com".to_string()),
+                    ])
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_heredoc() {
+        assert_eq!(
+            parse("if true:\n  cat <<EOF\n$x\n  EOF\nEOF\n  ls\nls"),
+            Some(vec![
+                Statement::If(
+                    "true".to_string(),
+                    vec![command("cat <<EOF\n$x\n  EOF\nEOF"), command("ls")],
+                    vec![]
+                ),
+                command("ls"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_if() {
+        assert_eq!(
+            parse("if true:\n  ls\n  pwd\nls"),
+            Some(vec![
+                Statement::If(
+                    "true".to_string(),
+                    vec![command("ls"), command("pwd")],
+                    vec![]
+                ),
+                command("ls"),
+            ])
+        );
+        assert_eq!(
+            parse("if true:\n  ls\nelse:\n  pwd\n"),
+            Some(vec![Statement::If(
+                "true".to_string(),
+                vec![command("ls")],
+                vec![command("pwd")]
+            )])
+        );
+        assert_eq!(
+            parse("if a:\n  ls\nelif b:\n  pwd\nelif c:\n\n  cd\nelse:\n  exit\nls"),
+            Some(vec![
+                Statement::If(
+                    "a".to_string(),
+                    vec![command("ls")],
+                    vec![Statement::If(
+                        "b".to_string(),
+                        vec![command("pwd")],
+                        vec![Statement::If(
+                            "c".to_string(),
+                            vec![command("cd")],
+                            vec![command("exit")]
+                        )]
+                    )]
+                ),
+                command("ls"),
+            ])
+        );
+        assert_eq!(
+            parse("if test ${x:-a} = a:\n  ls\nelif test \"$y\" = a:b:\n  pwd"),
+            Some(vec![Statement::If(
+                "test ${x:-a} = a".to_string(),
+                vec![command("ls")],
+                vec![Statement::If(
+                    "test \"$y\" = a:b".to_string(),
+                    vec![command("pwd")],
+                    vec![]
+                )]
+            )])
+        );
+        assert_eq!(
+            parse("#!/usr/bin/env dcsh\n\nif a:\n  # comment\n  if b:\n    ls\n  else:\n    pwd\nelse:\n  cd"),
+            Some(vec![Statement::If(
+                "a".to_string(),
+                vec![Statement::If(
+                    "b".to_string(),
+                    vec![command("ls")],
+                    vec![command("pwd")]
+                )],
+                vec![command("cd")]
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_indentation() {
+        assert_eq!(parse("echo start\n    echo indented\necho after\n"), None);
+        assert_eq!(parse("if a:\n    ls\n  pwd\n"), None);
+        assert_eq!(parse("if false:\necho DANGER\n"), None);
+        assert_eq!(parse("if a:\n  ls\nelif false:\necho DANGER\n"), None);
+        assert_eq!(parse("if a:\n  ls\nelse:\necho DANGER\n"), None);
+        assert_eq!(parse("else:\n  ls\n"), None);
+        assert_eq!(parse("while false:\necho DANGER\n"), None);
+        assert_eq!(parse("for x in a b:\necho DANGER\n"), None);
+        assert_eq!(
+            parse("ls\n\n    \n# comment\npwd"),
+            Some(vec![command("ls"), command("pwd")])
+        );
+    }
+
+    #[test]
+    fn test_parse_while() {
+        assert_eq!(
+            parse(
+                "while test -e lock:
+  sleep 1
+  while true:
+    break 2
+ls"
+            ),
+            Some(vec![
+                Statement::While(
+                    "test -e lock".to_string(),
+                    vec![
+                        command("sleep 1"),
+                        Statement::While("true".to_string(), vec![command("break 2")])
+                    ]
+                ),
+                command("ls"),
+            ])
+        );
+        assert_eq!(
+            parse("while test ${n:-0} -lt 3:\n  n = $((n + 1))"),
+            Some(vec![Statement::While(
+                "test ${n:-0} -lt 3".to_string(),
+                vec![assign("n", None, false, string("$((n + 1))"))]
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_for() {
+        assert_eq!(
+            parse("for f in *.log \"a b\" ${x:-y}:\n  rm $f\nfor x in:\n  ls"),
+            Some(vec![
+                Statement::For(
+                    "f".to_string(),
+                    "*.log \"a b\" ${x:-y}".to_string(),
+                    vec![command("rm $f")]
+                ),
+                Statement::For("x".to_string(), "".to_string(), vec![command("ls")]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_function() {
+        assert_eq!(
+            parse("def greet(name, greeting):\n  echo $greeting $name\nfn noop():\n  true\ngreet"),
+            Some(vec![
+                Statement::Function(Function {
+                    name: "greet".to_string(),
+                    parameters: vec!["name".to_string(), "greeting".to_string()],
+                    body: vec![command("echo $greeting $name")],
+                }),
+                Statement::Function(Function {
+                    name: "noop".to_string(),
+                    parameters: vec![],
+                    body: vec![command("true")],
+                }),
+                command("greet"),
+            ])
+        );
+        assert_eq!(parse("def bad(a b):\n  true"), None);
+        assert_eq!(parse("ls\nfn bad(a,):\nls"), None);
+        assert_eq!(parse("def empty():\nls"), None);
+    }
+
+    #[test]
+    fn test_parse_match() {
+        let glob = |pattern: &str| ArmPattern::Glob(pattern.to_string());
+        assert_eq!(
+            parse("match $1:\n  *.tar.gz:\n    tar xzf $1\n\n  start | \"re|start\":\n    up\n  /^v[0-9]+|x$/:\n    tag\n  _:\n    usage\nls"),
+            Some(vec![
+                Statement::Match(
+                    "$1".to_string(),
+                    vec![
+                        Arm {
+                            patterns: vec![glob("*.tar.gz")],
+                            body: vec![command("tar xzf $1")],
+                        },
+                        Arm {
+                            patterns: vec![glob("start"), glob("\"re|start\"")],
+                            body: vec![command("up")],
+                        },
+                        Arm {
+                            patterns: vec![ArmPattern::Regex("^v[0-9]+|x$".to_string())],
+                            body: vec![command("tag")],
+                        },
+                        Arm {
+                            patterns: vec![ArmPattern::Default],
+                            body: vec![command("usage")],
+                        },
+                    ]
+                ),
+                command("ls"),
+            ])
+        );
+        assert_eq!(parse("match x:\n  a | :\n    ls"), None);
+        assert_eq!(parse("match x:\nls"), None);
+        assert_eq!(parse("match x:\n  a:\nls"), None);
+    }
+
+    #[test]
+    fn test_parse_try() {
+        assert_eq!(
+            parse("try:\n  make\nexcept:\n  echo failed\n\nfinally:\n  rm -r build\ntry:\n  ls\nfinally:\n  pwd\nls"),
+            Some(vec![
+                Statement::Try(
+                    vec![command("make")],
+                    vec![command("echo failed")],
+                    vec![command("rm -r build")]
+                ),
+                Statement::Try(vec![command("ls")], vec![], vec![command("pwd")]),
+                command("ls"),
+            ])
+        );
+        assert_eq!(parse("try:\n  ls\nls"), None);
+        assert_eq!(parse("try:"), None);
+        assert_eq!(parse("ls\nexcept:\n  ls"), None);
+        assert_eq!(parse("try:\n  ls\nfinally:\n  ls\nfinally:\n  ls"), None);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::expand::Segment;
+
+/// Characters with a special meaning in patterns.
+const SPECIAL_CHARS: &[char] = &['*', '?', '[', ']', '\\'];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PatternToken {
+    Literal(char),
+    /// `?`
+    AnyChar,
+    /// `*`
+    AnyString,
+    /// `[abc]`, `[a-z]`, or a negated class like `[!abc]`
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+/// A shell pattern, as used for globbing, `match` arms and parameter expansion. A backslash makes
+/// the next character literal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    tokens: Vec<PatternToken>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = vec![];
+        let mut index = 0;
+        while index < chars.len() {
+            let token = match chars[index] {
+                '\\' if index + 1 < chars.len() => {
+                    index += 1;
+                    PatternToken::Literal(chars[index])
+                }
+                '?' => PatternToken::AnyChar,
+                '*' => PatternToken::AnyString,
+                '[' => match parse_class(&chars[index + 1..]) {
+                    Some((class, length)) => {
+                        index += length;
+                        class
+                    }
+                    None => PatternToken::Literal('['),
+                },
+                c => PatternToken::Literal(c),
+            };
+            tokens.push(token);
+            index += 1;
+        }
+        Pattern { tokens }
+    }
+
+    /// Builds a pattern from expanded word segments, where quoted text only matches itself.
+    pub fn from_segments(segments: &[Segment]) -> Pattern {
+        Pattern::new(&quote_segments(segments))
+    }
+
+    /// The only string the pattern matches, if it has no wildcards.
+    pub fn literal(&self) -> Option<String> {
+        self.tokens
+            .iter()
+            .map(|token| match token {
+                PatternToken::Literal(c) => Some(*c),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether the pattern matches the whole of `text`.
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        // Match greedily, going back to the most recent `*` to take one more character whenever
+        // the rest of the pattern fails
+        let (mut token, mut position) = (0, 0);
+        let mut backtrack = None;
+        while position < text.len() {
+            match self.tokens.get(token) {
+                Some(PatternToken::AnyString) => {
+                    backtrack = Some((token, position));
+                    token += 1;
+                    continue;
+                }
+                Some(single) if single_matches(single, text[position]) => {
+                    token += 1;
+                    position += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match backtrack {
+                Some((star, start)) => {
+                    token = star + 1;
+                    position = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            }
+        }
+        self.tokens[token..]
+            .iter()
+            .all(|token| *token == PatternToken::AnyString)
+    }
+}
+
+/// Parses the inside of a bracket expression, returning the class and how many characters it
+/// took up including the closing bracket. A `]` right at the start is part of the class.
+fn parse_class(chars: &[char]) -> Option<(PatternToken, usize)> {
+    let negated = matches!(chars.first(), Some('!' | '^'));
+    let mut index = negated as usize;
+    let mut ranges = vec![];
+    loop {
+        let c = *chars.get(index)?;
+        if c == ']' && index > negated as usize {
+            return Some((PatternToken::Class { negated, ranges }, index + 1));
+        }
+        if chars.get(index + 1) == Some(&'-') && chars.get(index + 2).is_some_and(|c| *c != ']') {
+            ranges.push((c, chars[index + 2]));
+            index += 3;
+        } else {
+            ranges.push((c, c));
+            index += 1;
+        }
+    }
+}
+
+fn single_matches(token: &PatternToken, c: char) -> bool {
+    match token {
+        PatternToken::Literal(literal) => *literal == c,
+        PatternToken::AnyChar => true,
+        PatternToken::AnyString => false,
+        PatternToken::Class { negated, ranges } => {
+            ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&c))
+                != *negated
+        }
+    }
+}
+
+/// Joins expanded word segments into the source of a pattern, escaping the quoted ones.
+pub fn quote_segments(segments: &[Segment]) -> String {
+    segments
+        .iter()
+        .map(|segment| match segment.quoted {
+            true => escape(&segment.text),
+            false => segment.text.clone(),
+        })
+        .collect()
+}
+
+/// Escapes every special character in `text`, so that it only matches itself as a pattern.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if SPECIAL_CHARS.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pattern() {
+        let matches = |pattern: &str, text: &str| Pattern::new(pattern).matches(text);
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "main.rso"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+        assert!(matches("?", "é"));
+        assert!(!matches("?", ""));
+        assert!(matches("**", ""));
+        assert!(matches("[a-c]x[!0-9]", "bxy"));
+        assert!(!matches("[a-c]x[!0-9]", "bx1"));
+        assert!(matches("[]a]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[", "["));
+        assert!(matches(r"\*\?", "*?"));
+        assert!(!matches(r"\*", "a"));
+        assert_eq!(Pattern::new(r"a\*").literal(), Some("a*".to_string()));
+        assert_eq!(Pattern::new("a?").literal(), None);
+        let segments = [Segment::new("*", false), Segment::new("*", true)];
+        assert!(Pattern::from_segments(&segments).matches("a*"));
+        assert!(!Pattern::from_segments(&segments).matches("ab"));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    io::{self, Write},
+    os::unix::process::ExitStatusExt,
+    process::{Child, ExitStatus},
+};
+
+/// A process started by the shell, either by spawning a program or by forking a subshell.
+#[derive(Debug)]
+pub enum Process {
+    Spawned(Child),
+    Forked(libc::pid_t),
+    /// An invocation that never started a process, e.g. because the program wasn't found.
+    Finished(i32),
+}
+
+impl Process {
+    pub fn id(&self) -> Option<u32> {
+        match self {
+            Process::Spawned(child) => Some(child.id()),
+            Process::Forked(pid) => Some(*pid as u32),
+            Process::Finished(_) => None,
+        }
+    }
+    /// Blocks until the process exits, returning its exit status.
+    pub fn wait(self) -> i32 {
+        match self {
+            Process::Spawned(mut child) => match child.wait() {
+                Ok(status) => exit_code(status),
+                Err(_) => 1,
+            },
+            Process::Forked(pid) => wait_pid(pid, 0).unwrap_or(1),
+            Process::Finished(status) => status,
+        }
+    }
+    /// Returns the exit status if the process has exited, without blocking.
+    pub fn try_wait(&mut self) -> Option<i32> {
+        match self {
+            Process::Spawned(child) => child.try_wait().ok().flatten().map(exit_code),
+            Process::Forked(pid) => {
+                let status = wait_pid(*pid, libc::WNOHANG)?;
+                *self = Process::Finished(status);
+                Some(status)
+            }
+            Process::Finished(status) => Some(*status),
+        }
+    }
+}
+
+/// Converts the status of a finished process into a shell exit status, where processes killed by
+/// a signal report 128 plus the signal number.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+fn wait_pid(pid: libc::pid_t, options: libc::c_int) -> Option<i32> {
+    let mut status = 0;
+    loop {
+        let result = unsafe { libc::waitpid(pid, &mut status, options) };
+        if result == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if result <= 0 {
+            // Either the process is still running or it has already been reaped
+            return if result == 0 { None } else { Some(1) };
+        }
+        if libc::WIFSIGNALED(status) {
+            return Some(128 + libc::WTERMSIG(status));
+        }
+        return Some(libc::WEXITSTATUS(status));
+    }
+}
+
+/// Runs `f` in a forked copy of the shell, which exits with the status `f` returns. This is how
+/// builtins and compound commands run as part of a pipeline or in the background.
+pub fn fork(f: impl FnOnce() -> i32) -> io::Result<Process> {
+    // Anything still buffered would otherwise be written by both processes
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            // Die quietly when writing to a closed pipe, like any other program
+            unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+            let status = f();
+            let _ = io::stdout().flush();
+            let _ = io::stderr().flush();
+            unsafe { libc::_exit(status) }
+        }
+        pid => Ok(Process::Forked(pid)),
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, PipeReader, Write},
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    thread,
+};
+
+use crate::command::ExecError;
+
+/// Where a redirection points a file descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedirectTarget {
+    /// `N< file`
+    Input(String),
+    /// `N> file`
+    Output(String),
+    /// `N>> file`
+    Append(String),
+    /// `N>&M` or `N<&M`
+    Duplicate(RawFd),
+    /// `N>&-`
+    Close,
+    /// `N<< DELIMITER`, whose body is expanded unless part of the delimiter was quoted
+    HereDoc { body: String, expand: bool },
+    /// `N<<< word`
+    HereString(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub fd: RawFd,
+    pub target: RedirectTarget,
+}
+
+impl Redirect {
+    pub fn new(fd: RawFd, target: RedirectTarget) -> Redirect {
+        Redirect { fd, target }
+    }
+}
+
+/// File descriptors of the shell that have been redirected, along with copies of what they
+/// pointed to before so that they can be restored. Redirections are applied to the shell itself
+/// so that builtins see them, and spawned programs simply inherit them.
+pub struct Redirections {
+    saved: Vec<(RawFd, Option<OwnedFd>)>,
+}
+
+impl Redirections {
+    pub fn new() -> Redirections {
+        Redirections { saved: vec![] }
+    }
+
+    /// Applies a list of redirections in order. If any of them fails, the ones already applied
+    /// are undone.
+    pub fn apply_all(redirects: &[Redirect]) -> Result<Redirections, ExecError> {
+        let mut redirections = Redirections::new();
+        for redirect in redirects {
+            if let Err(err) = redirections.apply(redirect) {
+                redirections.restore();
+                return Err(err);
+            }
+        }
+        Ok(redirections)
+    }
+
+    pub fn apply(&mut self, redirect: &Redirect) -> Result<(), ExecError> {
+        let open_err = |path: &String| {
+            let path = path.clone();
+            move |err| ExecError::Redirect(path, err)
+        };
+        // Open the target before touching any descriptors, so that a failure leaves them intact
+        let file = match &redirect.target {
+            RedirectTarget::Input(path) => File::open(path).map_err(open_err(path))?,
+            RedirectTarget::Output(path) => File::create(path).map_err(open_err(path))?,
+            RedirectTarget::Append(path) => OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .map_err(open_err(path))?,
+            RedirectTarget::Duplicate(source) => {
+                if !is_open(*source) {
+                    let err = io::Error::from_raw_os_error(libc::EBADF);
+                    return Err(ExecError::Redirect(source.to_string(), err));
+                }
+                self.save(redirect.fd);
+                duplicate(*source, redirect.fd)
+                    .map_err(|err| ExecError::Redirect(source.to_string(), err))?;
+                return Ok(());
+            }
+            RedirectTarget::Close => {
+                self.save(redirect.fd);
+                unsafe { libc::close(redirect.fd) };
+                return Ok(());
+            }
+            RedirectTarget::HereDoc { body, .. } | RedirectTarget::HereString(body) => {
+                let reader = here_document(body.clone())
+                    .map_err(|err| ExecError::Redirect(redirect.fd.to_string(), err))?;
+                return self.connect(redirect.fd, reader);
+            }
+        };
+        self.connect(redirect.fd, file)
+    }
+
+    /// Points `fd` at an already open file, such as one end of a pipe.
+    pub fn connect(&mut self, fd: RawFd, file: impl Into<OwnedFd>) -> Result<(), ExecError> {
+        let file = file.into();
+        self.save(fd);
+        if file.as_raw_fd() == fd {
+            // The file already has the right number, so it just needs to stay open, including in
+            // child processes
+            unsafe { libc::fcntl(fd, libc::F_SETFD, 0) };
+            let _ = file.into_raw_fd();
+            return Ok(());
+        }
+        duplicate(file.as_raw_fd(), fd).map_err(|err| ExecError::Redirect(fd.to_string(), err))
+    }
+
+    /// Puts every redirected file descriptor back the way it was.
+    pub fn restore(self) {
+        // Output written by builtins may still be buffered
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        for (fd, original) in self.saved.into_iter().rev() {
+            match original {
+                Some(original) => {
+                    let _ = duplicate(original.as_raw_fd(), fd);
+                }
+                None => {
+                    unsafe { libc::close(fd) };
+                }
+            }
+        }
+    }
+
+    fn save(&mut self, fd: RawFd) {
+        if self.saved.iter().any(|(saved, _)| *saved == fd) {
+            return;
+        }
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        // Keep the copy out of the way of low numbered descriptors, and out of child processes
+        let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+        let original = (copy != -1).then(|| unsafe { OwnedFd::from_raw_fd(copy) });
+        self.saved.push((fd, original));
+    }
+}
+
+/// Makes a pipe that reads back the given text. The text is written from another thread, so
+/// that a body bigger than the pipe's buffer doesn't block the shell.
+fn here_document(body: String) -> io::Result<PipeReader> {
+    let (reader, mut writer) = io::pipe()?;
+    thread::spawn(move || {
+        let _ = writer.write_all(body.as_bytes());
+    });
+    Ok(reader)
+}
+
+fn is_open(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+fn duplicate(source: RawFd, target: RawFd) -> io::Result<()> {
+    if source == target {
+        return Ok(());
+    }
+    if unsafe { libc::dup2(source, target) } == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
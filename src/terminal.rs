@@ -125,11 +125,8 @@ pub fn event_loop(context: &mut ExecContext) -> crossterm::Result<()> {
         if let Event::Key(event) = event::read()? {
             // println!("{:?}", event);
             if event.modifiers.contains(KeyModifiers::CONTROL) {
-                match event.code {
-                    KeyCode::Char('d') => {
-                        break;
-                    }
-                    _ => {}
+                if let KeyCode::Char('d') = event.code {
+                    break;
                 }
             } else {
                 match event.code {
@@ -150,6 +147,9 @@ pub fn event_loop(context: &mut ExecContext) -> crossterm::Result<()> {
                             println!("Invalid syntax");
                         }
                         history.push(command);
+                        if context.is_exiting() {
+                            break;
+                        }
                         terminal::enable_raw_mode()?;
                         prompt = Prompt::new();
                     }